@@ -1,27 +1,24 @@
-use std::io::prelude::*;
-use std::path::Path;
-use std::fs::{File, create_dir};
+use std::env;
+use std::process::Command;
 
-fn main() {
-    let dst = Path::new(concat!(env!("HOME"), "/.xr3wm/config.rs"));
-    if !dst.exists() {
-        match create_dir(dst.parent().unwrap()) {
-            Ok(_) => {
-                let mut f = File::create(&dst).unwrap();
-                f.write_all(b"#![allow(unused_imports)]
-extern crate xr3wm;
-
-use std::default::Default;
-use xr3wm::core::*;
+include!("src/source_hash.rs");
 
-#[no_mangle]
-pub extern fn configure(cfg: &mut Config) {
+fn main() {
+    // part of the abi fingerprint shared with the config dylib
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .map(|x| String::from_utf8_lossy(&x.stdout).trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=XR3WM_RUSTC_VERSION={}", version);
 
-}").unwrap();
-            }
-            Err(msg) => {
-                panic!("Failed to create config directory: {}", msg);
-            }
-        }
-    }
+    // the config is only built against sources with the same hash
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    let hash = source_hash(Path::new(&dir)).expect("failed to hash the xr3wm sources");
+    println!("cargo:rustc-env=XR3WM_SOURCE_HASH={}", hash);
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=RUSTC");
 }
@@ -0,0 +1,27 @@
+use std::mem::{align_of, size_of};
+use commands::{Cmd, ManageHook};
+use config::{Config, Keybinding, LogInfo, Statusbar, Swallow};
+use source_hash::{fnv, FNV_OFFSET};
+use spawn::Startup;
+use workspaces::WorkspaceConfig;
+
+/// Version of the xr3wm crate both the window manager and the config dylib have to be built from
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+
+/// Fingerprint of everything that has to match for `Config` to be safely passed
+/// between the window manager and the config dylib.
+pub fn fingerprint() -> u64 {
+    let layout = [
+        (size_of::<Config>(), align_of::<Config>()),
+        (size_of::<WorkspaceConfig>(), align_of::<WorkspaceConfig>()),
+        (size_of::<Keybinding>(), align_of::<Keybinding>()),
+        (size_of::<ManageHook>(), align_of::<ManageHook>()),
+        (size_of::<Statusbar>(), align_of::<Statusbar>()),
+        (size_of::<LogInfo>(), align_of::<LogInfo>()),
+        (size_of::<Startup>(), align_of::<Startup>()),
+        (size_of::<Swallow>(), align_of::<Swallow>()),
+        (size_of::<Cmd>(), align_of::<Cmd>()),
+    ];
+
+    fnv(FNV_OFFSET, format!("{}|{}|{}|{:?}", VERSION, env!("XR3WM_RUSTC_VERSION"), env!("XR3WM_SOURCE_HASH"), layout).as_bytes())
+}
@@ -0,0 +1,124 @@
+//! Starting the `startup` entries of the config and restarting the ones that should keep running.
+
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, Instant};
+use config::Config;
+use spawn::{self, Startup, StartupPolicy, STARTED_ENV};
+use utils;
+
+/// Entries exiting sooner after being started are considered broken and not restarted again
+const MIN_RUNTIME: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct Autostart {
+    // pids of the entries started in this session by key, kept in the environment for restarts
+    started: HashMap<String, u32>,
+    // entries to restart if they exit, by pid
+    supervised: HashMap<u32, (String, Instant)>,
+}
+
+fn is_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+// programs run in their own session, so the whole group is stopped including the children of a shell
+fn stop(pid: u32, key: &str) {
+    info!("stopping '{}'", key);
+    if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) } != 0 {
+        warn!("failed to stop '{}': {}", key, ::std::io::Error::last_os_error());
+    }
+}
+
+impl Autostart {
+    /// Picks up the entries started before xr3wm restarted in place
+    pub fn new() -> Autostart {
+        let started: HashMap<String, u32> = env::var(STARTED_ENV).ok()
+            .and_then(|x| ::serde_json::from_str(&x)
+                .map_err(|e| warn!("ignoring invalid {}: {}", STARTED_ENV, e))
+                .ok())
+            .unwrap_or_default();
+
+        Autostart {
+            started,
+            supervised: HashMap::new(),
+        }
+    }
+
+    /// Starts the entries of the config that are due, `ready` selects the entries waiting until
+    /// the window manager is ready instead of the ones started right away.
+    pub fn start(&mut self, config: &Config, ready: bool) {
+        for entry in config.startup.iter().filter(|x| x.wait_ready == ready) {
+            let key = entry.key();
+            let running = self.started.get(&key).cloned().filter(|&x| is_alive(x));
+
+            match (entry.policy, running) {
+                (StartupPolicy::Once, _) if self.started.contains_key(&key) => {}
+                (StartupPolicy::Restart, Some(pid)) => {
+                    self.supervised.entry(pid).or_insert_with(|| (key, Instant::now()));
+                }
+                (StartupPolicy::Reload, Some(pid)) => {
+                    stop(pid, &key);
+                    self.run(entry);
+                }
+                _ => self.run(entry),
+            }
+        }
+
+        self.save();
+    }
+
+    fn run(&mut self, entry: &Startup) {
+        let key = entry.key();
+        info!("starting '{}'", key);
+
+        match entry.spawn.spawn() {
+            Ok(pid) => {
+                if entry.policy == StartupPolicy::Restart {
+                    self.supervised.insert(pid, (key.clone(), Instant::now()));
+                }
+                self.started.insert(key, pid);
+            }
+            Err(e) => error!("{}", utils::concat_error_chain(&e)),
+        }
+    }
+
+    /// Collects exited programs and restarts the supervised entries among them
+    pub fn reap(&mut self, config: &Config) {
+        let mut changed = false;
+
+        for (pid, code) in spawn::reap() {
+            let (key, started) = match self.supervised.remove(&pid) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            // entries removed from the config or changed to another policy stay dead
+            let entry = config.startup.iter()
+                .find(|x| x.key() == key && x.policy == StartupPolicy::Restart);
+
+            match entry {
+                Some(_) if started.elapsed() < MIN_RUNTIME => {
+                    error!("'{}' exited with {} right after starting, not restarting it again", key, code);
+                }
+                Some(entry) => {
+                    warn!("'{}' exited with {}, restarting", key, code);
+                    self.run(entry);
+                    changed = true;
+                }
+                None => {}
+            }
+        }
+
+        if changed {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        match ::serde_json::to_string(&self.started) {
+            Ok(x) => env::set_var(STARTED_ENV, x),
+            Err(e) => error!("failed to save started programs: {}", e),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use clap::ArgMatches;
+use failure::*;
+use ipc::{IpcConnection, Reply};
+
+fn print_reply(reply: &Reply) -> bool {
+    if let Some(ref error) = reply.error {
+        eprintln!("{}", error);
+    }
+
+    if let Some(ref data) = reply.data {
+        match ::serde_json::to_string_pretty(data) {
+            Ok(x) => println!("{}", x),
+            Err(e) => eprintln!("failed to format reply: {}", e),
+        }
+    }
+
+    reply.success
+}
+
+fn values(matches: &ArgMatches, name: &str) -> String {
+    matches.values_of(name)
+        .map(|x| x.collect::<Vec<&str>>().join(" "))
+        .unwrap_or_default()
+}
+
+/// Runs the `msg`, `query` or `subscribe` subcommand against the running instance
+/// and returns whether it succeeded.
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+    let mut conn = IpcConnection::connect()
+        .context("failed to connect to xr3wm, is it running?")?;
+
+    match matches.subcommand() {
+        ("msg", Some(sub)) => {
+            let reply = conn.request(&values(sub, "command"))?;
+            Ok(print_reply(&reply))
+        }
+        ("query", Some(sub)) => {
+            let reply = conn.request(&format!("get-{}", sub.value_of("what").unwrap_or("tree")))?;
+            Ok(print_reply(&reply))
+        }
+        ("subscribe", Some(sub)) => {
+            let reply = conn.request(&format!("subscribe {}", values(sub, "events")))?;
+            if !print_reply(&reply) {
+                return Ok(false);
+            }
+
+            while let Some(line) = conn.read_line()? {
+                println!("{}", line);
+            }
+
+            bail!("connection closed by xr3wm")
+        }
+        (name, _) => bail!("unknown subcommand '{}'", name),
+    }
+}
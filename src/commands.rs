@@ -3,28 +3,61 @@
 extern crate libc;
 
 use self::libc::execvp;
-use std::{env, thread};
+use std::{env, fmt};
+use std::fs::remove_file;
+use std::str::FromStr;
 use std::ptr::null;
 use std::ffi::CString;
-use std::process::Command;
-use std::io::prelude::*;
-use std::path::Path;
-use std::fs::OpenOptions;
 use config::Config;
+use procfs::Procfs;
+use spawn::{quote, Program, Spawn};
+use paths;
 use layout::LayoutMsg;
 use xlib_window_system::XlibWindowSystem;
 use workspaces::{Workspaces, MoveOp};
 use xlib::Window;
 use failure::*;
 
+/// Set for the new process by `exec_self`, which picks up the saved workspace state
+pub const RESTART_ENV: &str = "XR3WM_RESTARTED";
+
+/// Window manager commands bound to keys or received over ipc.
+///
+/// Every command has a textual form parsed by `FromStr` and produced by `Display`:
+///
+/// ```text
+/// exec [--workspace <n>] [--env <name>=<value>]... [--cwd <dir>] [--focused-cwd] [--] <command line>
+///                               runs the command line with /bin/sh, `--workspace` puts
+///                               the first window of the program on workspace n,
+///                               `--focused-cwd` starts it where the focused terminal is.
+///                               Option values can be quoted like in a shell, the command
+///                               line after `-- ` is taken as it is
+/// switch-workspace <n>          workspaces and screens are counted from 1
+/// switch-screen <n>
+/// move-to-workspace <n>
+/// move-to-screen <n>
+/// layout-msg <layout message>   see LayoutMsg, `layout` is accepted as alias
+/// reload                        swaps in the rebuilt config
+/// restart                       rebuilds the config and restarts xr3wm in place
+/// exit
+/// kill-client                   `kill` is accepted as alias
+/// focus-up | focus-down | focus-master
+/// swap-up | swap-down | swap-master
+/// ```
+#[derive(PartialEq)]
 pub enum Cmd {
     Exec(String),
+    /// `Exec` with environment and working directory, or a plain argument list
+    Spawn(Spawn),
+    /// Spawns a program and puts its first window on the workspace
+    SpawnOn(usize, Spawn),
     SwitchWorkspace(usize),
     SwitchScreen(usize),
     MoveToWorkspace(usize),
     MoveToScreen(usize),
     SendLayoutMsg(LayoutMsg),
     Reload,
+    Restart,
     Exit,
     KillClient,
     FocusUp,
@@ -40,7 +73,16 @@ impl Cmd {
         match *self {
             Cmd::Exec(ref cmd) => {
                 debug!("Cmd::Exec: {}", cmd);
-                exec(cmd.clone());
+                Spawn::shell(cmd).spawn()?;
+            }
+            Cmd::Spawn(ref spawn) => {
+                debug!("Cmd::Spawn: {}", spawn);
+                resolve_cwd(spawn, ws, workspaces).spawn()?;
+            }
+            Cmd::SpawnOn(index, ref spawn) => {
+                // placing the window is up to the event loop which keeps track of started programs
+                debug!("Cmd::SpawnOn: {}, {}", index, spawn);
+                resolve_cwd(spawn, ws, workspaces).spawn()?;
             }
             Cmd::SwitchWorkspace(index) => {
                 debug!("Cmd::SwitchWorkspace: {}", index);
@@ -63,10 +105,9 @@ impl Cmd {
                 workspaces.current_mut().send_layout_message(msg.clone());
                 workspaces.current().redraw(ws, config);
             }
-            Cmd::Reload => {
-                debug!("Cmd::Reload");
-                reload(workspaces)
-                    .context("failed to reload xr3wm")?;
+            Cmd::Reload | Cmd::Restart => {
+                // the config is rebuilt in the background, see `Reloader`
+                bail!("'{}' is run by the event loop", self);
             }
             Cmd::Exit => {
                 debug!("Cmd::Exit");
@@ -107,53 +148,264 @@ impl Cmd {
     }
 }
 
-fn reload(workspaces: &mut Workspaces) -> Result<(), Error> {
-    info!("recompiling...");
+// splits off the first whitespace separated word, trailing whitespace stays with the rest
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(pos) => (&s[..pos], s[pos..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+// splits off the first word of an option value, quotes and backslashes work like in a shell
+// so values written with `spawn::quote` come back unchanged
+fn split_quoted(s: &str) -> Result<(String, &str), Error> {
+    let s = s.trim_start();
+    let mut word = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '\'' => loop {
+                match chars.next() {
+                    Some((_, '\'')) => break,
+                    Some((_, c)) => word.push(c),
+                    None => bail!("unterminated quote in '{}'", s),
+                }
+            },
+            // like in a shell, a backslash only escapes the characters special within double quotes
+            '"' => loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) if "\"\\$`".contains(c) => word.push(c),
+                        Some((_, c)) => {
+                            word.push('\\');
+                            word.push(c);
+                        }
+                        None => bail!("unterminated quote in '{}'", s),
+                    },
+                    Some((_, c)) => word.push(c),
+                    None => bail!("unterminated quote in '{}'", s),
+                }
+            },
+            '\\' => match chars.next() {
+                Some((_, c)) => word.push(c),
+                None => bail!("trailing backslash in '{}'", s),
+            },
+            c if c.is_whitespace() => return Ok((word, &s[pos..])),
+            c => word.push(c),
+        }
+    }
+
+    Ok((word, ""))
+}
+
+fn write_exec(f: &mut fmt::Formatter, workspace: Option<usize>, spawn: &Spawn) -> fmt::Result {
+    write!(f, "exec")?;
+    if let Some(index) = workspace {
+        write!(f, " --workspace {}", index)?;
+    }
+    for (name, value) in spawn.env.iter() {
+        write!(f, " --env {}", quote(&format!("{}={}", name, value)))?;
+    }
+    if let Some(ref dir) = spawn.cwd {
+        write!(f, " --cwd {}", quote(&dir.to_string_lossy()))?;
+    }
+    if spawn.focused_cwd {
+        write!(f, " --focused-cwd")?;
+    }
+    write!(f, " -- {}", spawn)
+}
+
+/// Replaces the working directory of a spawn with `focused_cwd` by the one of the focused window,
+/// `cwd` stays as fallback if it can't be determined
+pub fn resolve_cwd(spawn: &Spawn, ws: &XlibWindowSystem, workspaces: &Workspaces) -> Spawn {
+    let mut spawn = spawn.clone();
+
+    if spawn.focused_cwd {
+        let window = workspaces.current().focused_window();
+        match ws.get_window_pid(window).and_then(|pid| Procfs::default().shell_cwd(pid)) {
+            Some(dir) => {
+                debug!("starting in {}", dir.display());
+                spawn.cwd = Some(dir);
+            }
+            None => debug!("no working directory found for window {}", window),
+        }
+    }
+
+    spawn
+}
+
+fn parse_index(name: &str, arg: &str) -> Result<usize, Error> {
+    match arg.parse::<usize>() {
+        Ok(x) if x > 0 => Ok(x),
+        _ => bail!("'{}' expects a number greater than 0, got '{}'", name, arg),
+    }
+}
 
-    let config_build_dir = concat!(env!("HOME"), "/.xr3wm/.build");
-    let mut cmd = Command::new("cargo");
+fn parse_exec(raw: &str) -> Result<Cmd, Error> {
+    let mut spawn = Spawn::shell("");
+    let mut workspace = None;
+    let mut rest = raw.trim_start();
 
-    let output = cmd.current_dir(&config_build_dir)
-        .arg("build")
-        .env("RUST_LOG", "none")
-        .output()
-        .context("failed to run cargo")?;
+    let line = loop {
+        match split_word(rest) {
+            ("--", _) => {
+                // only the separator is dropped, the command line may start with whitespace
+                let mut chars = rest[2..].chars();
+                chars.next();
+                break chars.as_str();
+            }
+            ("--env", tail) => {
+                let (var, tail) = split_quoted(tail)?;
+                let pos = var.find('=')
+                    .ok_or_else(|| format_err!("'--env' expects <name>=<value>, got '{}'", var))?;
+                spawn = spawn.env(&var[..pos], &var[pos + 1..]);
+                rest = tail.trim_start();
+            }
+            ("--workspace", tail) => {
+                let (index, tail) = split_word(tail);
+                workspace = Some(parse_index("--workspace", index)?);
+                rest = tail;
+            }
+            ("--focused-cwd", tail) => {
+                spawn = spawn.focused_cwd();
+                rest = tail;
+            }
+            ("--cwd", tail) => {
+                let (dir, tail) = split_quoted(tail)?;
+                if dir.is_empty() {
+                    bail!("'--cwd' expects a directory");
+                }
+                spawn = spawn.cwd(dir);
+                rest = tail.trim_start();
+            }
+            (x, _) if x.starts_with("--") => bail!("unknown exec option '{}'", x),
+            ("", _) => bail!("'exec' expects a command line"),
+            _ => break rest.trim_end(),
+        }
+    };
+
+    if workspace.is_none() && spawn.env.is_empty() && spawn.cwd.is_none() && !spawn.focused_cwd {
+        Ok(Cmd::Exec(line.to_string()))
+    } else {
+        spawn.program = Program::Shell(line.to_string());
+        Ok(match workspace {
+            Some(index) => Cmd::SpawnOn(index, spawn),
+            None => Cmd::Spawn(spawn),
+        })
+    }
+}
+
+impl FromStr for Cmd {
+    type Err = Error;
 
-    if !output.status.success() {
-        let stderr_msg = String::from_utf8(output.stderr)
-            .context("failed to convert cargo stderr to UTF-8")?;
-        bail!(format_err!("failed to recompile: {}", stderr_msg))
+    fn from_str(s: &str) -> Result<Cmd, Error> {
+        // exec command lines and custom layout messages keep their whitespace
+        let s = s.trim_start();
+        let (name, raw) = match s.find(char::is_whitespace) {
+            Some(pos) => (&s[..pos], &s[pos..]),
+            None => (s, ""),
+        };
+        let arg = raw.trim();
+
+        let cmd = match name {
+            "exec" => parse_exec(raw)?,
+            "switch-workspace" => Cmd::SwitchWorkspace(parse_index(name, arg)?),
+            "switch-screen" => Cmd::SwitchScreen(parse_index(name, arg)?),
+            "move-to-workspace" => Cmd::MoveToWorkspace(parse_index(name, arg)?),
+            "move-to-screen" => Cmd::MoveToScreen(parse_index(name, arg)?),
+            "layout-msg" | "layout" => Cmd::SendLayoutMsg(raw.parse::<LayoutMsg>()
+                .map_err(|e| format_err!("'{}': {}", name, e))?),
+            "reload" => Cmd::Reload,
+            "restart" => Cmd::Restart,
+            "exit" => Cmd::Exit,
+            "kill-client" | "kill" => Cmd::KillClient,
+            "focus-up" => Cmd::FocusUp,
+            "focus-down" => Cmd::FocusDown,
+            "focus-master" => Cmd::FocusMaster,
+            "swap-up" => Cmd::SwapUp,
+            "swap-down" => Cmd::SwapDown,
+            "swap-master" => Cmd::SwapMaster,
+            "" => bail!("empty command"),
+            x => bail!("unknown command '{}'", x),
+        };
+
+        match cmd {
+            Cmd::Exec(_) | Cmd::Spawn(_) | Cmd::SpawnOn(..) | Cmd::SwitchWorkspace(_) | Cmd::SwitchScreen(_) | Cmd::MoveToWorkspace(_) |
+            Cmd::MoveToScreen(_) | Cmd::SendLayoutMsg(_) => {}
+            _ if !arg.is_empty() => bail!("'{}' takes no arguments, got '{}'", name, arg),
+            _ => {}
+        }
+
+        Ok(cmd)
     }
+}
 
-    debug!("Cmd::Reload: restarting xr3wm...");
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cmd::Exec(ref cmd) => {
+                if cmd.is_empty() || cmd.trim() != cmd || cmd.starts_with("--") {
+                    write!(f, "exec -- {}", cmd)
+                } else {
+                    write!(f, "exec {}", cmd)
+                }
+            }
+            Cmd::Spawn(ref spawn) => write_exec(f, None, spawn),
+            Cmd::SpawnOn(index, ref spawn) => write_exec(f, Some(index), spawn),
+            Cmd::SwitchWorkspace(x) => write!(f, "switch-workspace {}", x),
+            Cmd::SwitchScreen(x) => write!(f, "switch-screen {}", x),
+            Cmd::MoveToWorkspace(x) => write!(f, "move-to-workspace {}", x),
+            Cmd::MoveToScreen(x) => write!(f, "move-to-screen {}", x),
+            Cmd::SendLayoutMsg(ref msg) => write!(f, "layout-msg {}", msg),
+            Cmd::Reload => write!(f, "reload"),
+            Cmd::Restart => write!(f, "restart"),
+            Cmd::Exit => write!(f, "exit"),
+            Cmd::KillClient => write!(f, "kill-client"),
+            Cmd::FocusUp => write!(f, "focus-up"),
+            Cmd::FocusDown => write!(f, "focus-down"),
+            Cmd::FocusMaster => write!(f, "focus-master"),
+            Cmd::SwapUp => write!(f, "swap-up"),
+            Cmd::SwapDown => write!(f, "swap-down"),
+            Cmd::SwapMaster => write!(f, "swap-master"),
+        }
+    }
+}
 
-    let path = Path::new(concat!(env!("HOME"), "/.xr3wm/.tmp"));
+/// Saves the workspace state and replaces the process with a new instance loading the current config
+pub fn restart(ws: &XlibWindowSystem, workspaces: &Workspaces) -> Result<(), Error> {
+    debug!("restarting xr3wm...");
 
     // save current workspace state to load on restart
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&path)
-        .context("failed to open workspace state tmp file")?;
-    file.write_all(workspaces.serialize().as_bytes())
+    workspaces.save_state(ws)
+        .save(&paths::workspace_state())
         .context("failed to save workspace state")?;
-    file.flush()
-        .context("failed to flush workspace tmp file")?;
 
+    exec_self()
+}
+
+/// Replaces the running process with xr3wm started with the same arguments
+pub fn exec_self() -> Result<(), Error> {
     let mut args: Vec<*const libc::c_char> = env::args()
         .filter_map(|x| CString::new(x).ok())
         .map(|x| x.into_raw() as *const libc::c_char)
         .collect();
     args.push(null());
 
+    env::set_var(RESTART_ENV, "1");
     unsafe {
         execvp(args[0] as *const libc::c_char, args.as_ptr());
-        // execvp returns only if an error has occurred
-        error!("failed to reload: {}", ::std::io::Error::last_os_error());
     }
 
-    Ok(())
+    // execvp returns only if an error has occurred, the state saved for the new process
+    // must not be picked up by the next login
+    let err = ::std::io::Error::last_os_error();
+    env::remove_var(RESTART_ENV);
+    remove_file(paths::workspace_state()).ok();
+
+    Err(err.context("failed to execute xr3wm").into())
 }
 
 pub struct ManageHook {
@@ -161,6 +413,7 @@ pub struct ManageHook {
     pub cmd: CmdManage,
 }
 
+/// Action of a manage hook, only `Move` is supported so far
 pub enum CmdManage {
     Move(usize),
     Float,
@@ -169,47 +422,134 @@ pub enum CmdManage {
 }
 
 impl CmdManage {
+    pub fn is_supported(&self) -> bool {
+        matches!(*self, CmdManage::Move(_))
+    }
+}
+
+impl FromStr for CmdManage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<CmdManage, Error> {
+        let (name, arg) = split_word(s.trim());
+
+        let cmd = match name {
+            "move" => CmdManage::Move(parse_index(name, arg)?),
+            "float" | "fullscreen" | "ignore" => bail!("manage hook action '{}' is not supported yet", name),
+            x => bail!("unknown manage hook action '{}'", x),
+        };
+
+        if name != "move" && !arg.is_empty() {
+            bail!("'{}' takes no arguments, got '{}'", name, arg);
+        }
+
+        Ok(cmd)
+    }
+}
+
+impl CmdManage {
+    /// Manages the window, `workspace` is an index overriding the workspace the hook would choose
     pub fn call(&self,
                 ws: &XlibWindowSystem,
                 workspaces: &mut Workspaces,
                 config: &Config,
-                window: Window) {
+                window: Window,
+                workspace: Option<usize>) {
         match *self {
             CmdManage::Move(index) => {
                 debug!("CmdManage::Move: {}, {}", window, index);
-                workspaces.add_window(Some(index - 1), ws, config, window);
+                workspaces.add_window(Some(workspace.unwrap_or(index - 1)), ws, config, window);
             }
-            CmdManage::Float => {
-                debug!("CmdManage::Float");
-                unimplemented!()
-            }
-            CmdManage::Fullscreen => {
-                debug!("CmdManage::Fullscreen");
-                unimplemented!()
-            }
-            CmdManage::Ignore => {
-                debug!("CmdManage::Ignore");
-                unimplemented!()
+            // rejected by the config validation, the window is managed as usual just in case
+            CmdManage::Float | CmdManage::Fullscreen | CmdManage::Ignore => {
+                error!("unsupported manage hook action for window {}", window);
+                workspaces.add_window(workspace, ws, config, window);
             }
         }
     }
 }
 
-fn exec(cmd: String) {
-    thread::spawn(move || {
-        let args: Vec<&str> = cmd[..].split(' ').collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if !args.is_empty() {
-            let mut cmd = Command::new(args[0]);
+    fn round_trip(cmd: Cmd) {
+        let text = cmd.to_string();
+        match text.parse::<Cmd>() {
+            Ok(parsed) => assert!(parsed == cmd, "'{}' parsed back as '{}'", text, parsed),
+            Err(e) => panic!("failed to parse '{}': {}", text, e),
+        }
+    }
 
-            if args.len() > 1 {
-                cmd.args(&args[1..]);
-            }
+    #[test]
+    fn exec_round_trip() {
+        for line in &["xterm", "xterm -e 'a b'", "foo ", " foo", "a  b", "", "--foo bar", "-- x"] {
+            round_trip(Cmd::Exec(line.to_string()));
+        }
+    }
 
-            match cmd.output() {
-                Ok(_) => (),
-                _ => panic!("failed to start \"{:?}\"", cmd),
-            }
+    #[test]
+    fn spawn_round_trip() {
+        round_trip(Cmd::Spawn(Spawn::shell("urxvt -e htop").env("TERM", "xterm")));
+        round_trip(Cmd::Spawn(Spawn::shell("echo").env("MSG", "it's a b").env("A", "b=c").env("BS", "a\\b")));
+        round_trip(Cmd::Spawn(Spawn::shell(" mpv ").cwd("~/my videos")));
+        round_trip(Cmd::Spawn(Spawn::shell("").cwd("/tmp")));
+        round_trip(Cmd::Spawn(Spawn::shell("urxvt").focused_cwd().cwd("--not-an-option")));
+        round_trip(Cmd::SpawnOn(2, Spawn::shell("firefox")));
+        round_trip(Cmd::SpawnOn(3, Spawn::shell("slack --silent").env("GDK_SCALE", "2").focused_cwd()));
+    }
+
+    #[test]
+    fn double_quotes() {
+        let cmd: Cmd = "exec --env \"MSG=hello world\" notify-send x".parse().unwrap();
+        assert!(cmd == Cmd::Spawn(Spawn::shell("notify-send x").env("MSG", "hello world")));
+
+        let cmd: Cmd = r#"exec --cwd "a \"b\" \\c \d" --env A="x"'y' -- ls"#.parse().unwrap();
+        assert!(cmd == Cmd::Spawn(Spawn::shell("ls").env("A", "xy").cwd(r#"a "b" \c \d"#)));
+    }
+
+    #[test]
+    fn argv_is_written_as_shell_line() {
+        let cmd = Cmd::Spawn(Spawn::argv(&["echo", "a b", "it's"]).cwd("/tmp"));
+        let text = cmd.to_string();
+        let parsed: Cmd = text.parse().unwrap();
+
+        assert_eq!(text, "exec --cwd /tmp -- echo 'a b' 'it'\\''s'");
+        assert!(parsed == Cmd::Spawn(Spawn::shell("echo 'a b' 'it'\\''s'").cwd("/tmp")));
+    }
+
+    #[test]
+    fn simple_round_trip() {
+        let cmds = vec![
+            Cmd::SwitchWorkspace(3),
+            Cmd::SwitchScreen(1),
+            Cmd::MoveToWorkspace(12),
+            Cmd::MoveToScreen(2),
+            Cmd::SendLayoutMsg(LayoutMsg::NextLayout),
+            Cmd::SendLayoutMsg(LayoutMsg::Custom(" x ".to_string())),
+            Cmd::SendLayoutMsg(LayoutMsg::Custom(String::new())),
+            Cmd::Reload,
+            Cmd::Restart,
+            Cmd::Exit,
+            Cmd::KillClient,
+            Cmd::FocusUp,
+            Cmd::FocusDown,
+            Cmd::FocusMaster,
+            Cmd::SwapUp,
+            Cmd::SwapDown,
+            Cmd::SwapMaster,
+        ];
+
+        for cmd in cmds {
+            round_trip(cmd);
         }
-    });
+    }
+
+    #[test]
+    fn invalid_commands() {
+        for text in &["", "exec", "exec --env", "exec --env FOO -- x", "exec --cwd 'a -- x", "exec --env \"A=b -- x",
+                      "exec --bogus x", "switch-workspace 0", "switch-workspace x", "reload now", "layout-msg custom", "layout-msg foo"] {
+            assert!(text.parse::<Cmd>().is_err(), "'{}' should not parse", text);
+        }
+    }
 }
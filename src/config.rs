@@ -1,17 +1,41 @@
 #![allow(unused)]
 
 use std::default::Default;
-use std::io::{self, Write};
-use std::path::Path;
-use std::fs::{File, create_dir};
-use std::process::{Command, Child, Stdio};
+use std::env;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::fs::{File, copy, create_dir_all, remove_file};
+use std::process::{self, Command, Child, Stdio};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use failure::*;
 use layout::*;
 use keycode::*;
 use workspaces::{Workspaces, WorkspaceConfig};
 use xlib_window_system::XlibWindowSystem;
-use commands::{Cmd, ManageHook};
+use commands::{Cmd, CmdManage, ManageHook};
+use spawn::{self, Startup};
+use toml_config::TomlConfig;
 use libloading::{Library, Symbol};
+use std::ffi::CStr;
+use abi;
+use paths;
+use source_hash::source_hash;
+
+// number of configs loaded by `Config::reload`
+static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+const DEFAULT_CONFIG: &str = "#![allow(unused_imports)]
+extern crate xr3wm;
+
+use std::default::Default;
+use xr3wm::core::*;
+
+#[no_mangle]
+pub extern fn configure(cfg: &mut Config) {
+
+}
+";
 
 pub struct Keybinding {
     pub mods: u8,
@@ -32,13 +56,19 @@ pub struct LogInfo {
     pub workspaces: Vec<WorkspaceInfo>,
     pub layout_name: String,
     pub window_title: String,
+    /// Status message of the window manager itself, e.g. about a running reload
+    pub message: Option<String>,
 }
 
+// pid of the running statusbar, so it can still be stopped when the X connection is lost
+static STATUSBAR_PID: AtomicU32 = AtomicU32::new(0);
+
 pub struct Statusbar {
     child: Option<Child>,
     executable: String,
     args: Option<Vec<String>>,
     fn_format: Box<dyn Fn(LogInfo) -> String>,
+    message: Option<String>,
 }
 
 impl Statusbar {
@@ -51,35 +81,42 @@ impl Statusbar {
             executable,
             args,
             fn_format,
+            message: None,
         }
     }
 
     pub fn xmobar() -> Statusbar {
         Statusbar::new("xmobar".to_string(),
                        None,
-                       Box::new(move |info: LogInfo| -> String {
-            let workspaces = info.workspaces
-                .iter()
-                .map(|x| {
-                    let (fg, bg) = if x.current {
-                        ("#00ff00", "#000000")
-                    } else if x.visible {
-                        ("#009900", "#000000")
-                    } else if x.urgent {
-                        ("#ff0000", "#000000")
-                    } else {
-                        ("#ffffff", "#000000")
-                    };
-                    format!("<fc={},{}>[{}]</fc>", fg, bg, x.tag)
-                })
-                .collect::<Vec<String>>()
-                .join(" ");
-
-            format!("{} | {} | {}\n",
-                    workspaces,
-                    info.layout_name,
-                    info.window_title)
-        }))
+                       Box::new(Statusbar::xmobar_format))
+    }
+
+    pub fn xmobar_format(info: LogInfo) -> String {
+        let workspaces = info.workspaces
+            .iter()
+            .map(|x| {
+                let (fg, bg) = if x.current {
+                    ("#00ff00", "#000000")
+                } else if x.visible {
+                    ("#009900", "#000000")
+                } else if x.urgent {
+                    ("#ff0000", "#000000")
+                } else {
+                    ("#ffffff", "#000000")
+                };
+                format!("<fc={},{}>[{}]</fc>", fg, bg, x.tag)
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        match info.message {
+            Some(msg) => format!("{} | {} | <fc=#ffff00>{}</fc>\n", workspaces, info.layout_name, msg),
+            None => format!("{} | {} | {}\n", workspaces, info.layout_name, info.window_title),
+        }
+    }
+
+    pub fn set_message(&mut self, message: Option<String>) {
+        self.message = message;
     }
 
     pub fn start(&mut self) -> Result<(), Error> {
@@ -94,12 +131,49 @@ impl Statusbar {
             cmd.args(self.args.clone().expect("args missing").as_slice());
         }
 
-        self.child = Some(cmd.stdin(Stdio::piped()).spawn()
-            .context(format!("failed to execute '{}'", self.executable))?);
+        let child = cmd.stdin(Stdio::piped()).spawn()
+            .context(format!("failed to execute '{}'", self.executable))?;
+        STATUSBAR_PID.store(child.id(), Ordering::SeqCst);
+        spawn::own(child.id());
+        self.child = Some(child);
 
         Ok(())
     }
 
+    pub fn stop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            debug!("stopping statusbar {}", self.executable);
+            STATUSBAR_PID.store(0, Ordering::SeqCst);
+            child.kill().ok();
+            child.wait().ok();
+            spawn::release(child.id());
+        }
+    }
+
+    /// Kills the running statusbar without access to the config, for when xr3wm has to exit
+    /// from within an xlib callback
+    pub fn kill_running() {
+        let pid = STATUSBAR_PID.swap(0, Ordering::SeqCst);
+        if pid != 0 {
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGKILL);
+                libc::waitpid(pid as libc::pid_t, ::std::ptr::null_mut(), 0);
+            }
+        }
+    }
+
+    /// Takes over the process of a statusbar from the previous config if the command is the same,
+    /// restarts it otherwise
+    pub fn replace(&mut self, old: &mut Statusbar) -> Result<(), Error> {
+        if self.executable == old.executable && self.args == old.args {
+            self.child = old.child.take();
+            Ok(())
+        } else {
+            old.stop();
+            self.start()
+        }
+    }
+
     pub fn update(&mut self, ws: &XlibWindowSystem, workspaces: &Workspaces) -> Result<(), Error> {
         if self.child.is_none() {
             return Ok(());
@@ -122,6 +196,7 @@ impl Statusbar {
                 .collect(),
             layout_name: workspaces.current().get_layout().name(),
             window_title: ws.get_window_title(workspaces.current().focused_window()),
+            message: self.message.clone(),
         });
 
         let stdin = self.child.as_mut()
@@ -137,6 +212,38 @@ impl Statusbar {
     }
 }
 
+/// Severity of a problem found by `Config::validate`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Problem found by `Config::validate`
+#[derive(Debug)]
+pub struct ConfigIssue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Terminals hiding behind the windows of programs started from them until those close
+#[derive(Default)]
+pub struct Swallow {
+    /// Classes of the terminals that can be swallowed, swallowing is off if empty
+    pub terminals: Vec<String>,
+    /// Classes of windows that never swallow their terminal
+    pub exclude: Vec<String>,
+}
+
 pub struct Config {
     pub workspaces: Vec<WorkspaceConfig>,
     pub mod_key: u8,
@@ -145,8 +252,15 @@ pub struct Config {
     pub border_focus_color: u32,
     pub border_urgent_color: u32,
     pub greedy_view: bool,
+    /// Reload automatically whenever the config file is saved
+    pub watch_config: bool,
+    /// Put applications back on their workspaces when they are started again after logging in
+    pub session_restore: bool,
     pub keybindings: Vec<Keybinding>,
     pub manage_hooks: Vec<ManageHook>,
+    /// Programs started together with xr3wm
+    pub startup: Vec<Startup>,
+    pub swallow: Swallow,
     pub statusbar: Option<Statusbar>,
 }
 
@@ -168,6 +282,8 @@ impl Default for Config {
             border_focus_color: 0x002a_82e6,
             border_urgent_color: 0x00ff_0000,
             greedy_view: false,
+            watch_config: false,
+            session_restore: false,
             keybindings: vec![Keybinding {
                                   mods: 0,
                                   key: "Return".to_string(),
@@ -249,6 +365,8 @@ impl Default for Config {
                                   cmd: Cmd::Reload,
                               }],
             manage_hooks: Vec::new(),
+            startup: Vec::new(),
+            swallow: Swallow::default(),
             statusbar: None,
         };
 
@@ -266,7 +384,7 @@ impl Default for Config {
             });
         }
 
-        for &(i, key) in vec![(1, "w"), (2, "e"), (3, "r")].iter() {
+        for &(i, key) in [(1, "w"), (2, "e"), (3, "r")].iter() {
             config.keybindings.push(Keybinding {
                 mods: 0,
                 key: key.to_string(),
@@ -285,35 +403,96 @@ impl Default for Config {
 }
 
 impl Config {
-    fn compile() -> Result<(), Error> {
-        let dst = Path::new(concat!(env!("HOME"), "/.xr3wm/.build"));
-        if !dst.exists() {
-            create_dir(dst)
-                .context("failed to create config build directory")?
+    // locates the xr3wm sources the config crate gets built against
+    fn source_dir() -> Result<PathBuf, Error> {
+        let candidates = vec![
+            // explicit override, e.g. for distribution packages shipping the sources elsewhere
+            env::var_os("XR3WM_SOURCE_DIR").map(PathBuf::from),
+            // vendored copy
+            Some(paths::vendored_source_dir()),
+            // the sources this binary was built from
+            Some(PathBuf::from(env!("CARGO_MANIFEST_DIR"))),
+        ];
+
+        for dir in candidates.into_iter().flatten() {
+            if !dir.join("Cargo.toml").exists() {
+                continue;
+            }
+
+            // the version is never bumped, only identical sources are guaranteed to match
+            let hash = source_hash(&dir)
+                .context(format!("failed to hash the sources in {}", dir.display()))?;
+            if hash == env!("XR3WM_SOURCE_HASH") {
+                return Ok(dir);
+            }
+
+            warn!("ignoring xr3wm sources at {}: they differ from the sources xr3wm was built from ({} instead of {})",
+                  dir.display(), hash, env!("XR3WM_SOURCE_HASH"));
         }
 
-        if !dst.join("Cargo.toml").exists() {
-            let mut f = File::create(dst.join("Cargo.toml"))
-                .context("failed to create Cargo.toml")?;
+        bail!("failed to find the xr3wm {} sources xr3wm was built from, set XR3WM_SOURCE_DIR or copy them to {}",
+              env!("CARGO_PKG_VERSION"),
+              paths::vendored_source_dir().display())
+    }
+
+    /// Prepares the config crate and returns the cargo command building it
+    pub(crate) fn build_command() -> Result<Command, Error> {
+        let dst = paths::build_dir();
+        create_dir_all(&dst)
+            .context("failed to create config build directory")?;
 
-            f.write_all(b"[project]
+        let src = Config::source_dir()?;
+        debug!("building config against {}", src.display());
+
+        let manifest = format!("[package]
 name = \"config\"
 version = \"0.0.1\"
 authors = [\"xr3wm\"]
 
 [dependencies.xr3wm]
-git = \"https://github.com/tsurai/xr3wm.git\"
+path = {:?}
 
 [lib]
 name = \"config\"
-path = \"../config.rs\"
-crate-type = [\"dylib\"]")
+path = {:?}
+crate-type = [\"dylib\"]
+", src.to_string_lossy(), paths::config_rs().to_string_lossy());
+
+        // rewrite manifests from older versions depending on the git repository
+        let mut old_manifest = String::new();
+        File::open(dst.join("Cargo.toml"))
+            .and_then(|mut f| f.read_to_string(&mut old_manifest))
+            .ok();
+
+        if old_manifest != manifest {
+            let mut f = File::create(dst.join("Cargo.toml"))
+                .context("failed to create Cargo.toml")?;
+
+            f.write_all(manifest.as_bytes())
                 .context("failed to write Cargo.toml")?;
         }
 
-        let output = Command::new("cargo")
-            .arg("build")
-            .current_dir(dst)
+        // start out with the dependency versions the running binary was built with, the lock
+        // file of the config crate is its own afterwards
+        if src.join("Cargo.lock").exists() && !dst.join("Cargo.lock").exists() {
+            copy(src.join("Cargo.lock"), dst.join("Cargo.lock"))
+                .context("failed to copy Cargo.lock")?;
+        }
+
+        let mut cmd = Command::new("cargo");
+        cmd.arg("build")
+            .env("RUST_LOG", "none")
+            .current_dir(&dst);
+
+        if env::var_os("XR3WM_OFFLINE").is_some() {
+            cmd.arg("--offline");
+        }
+
+        Ok(cmd)
+    }
+
+    pub(crate) fn compile() -> Result<(), Error> {
+        let output = Config::build_command()?
             .output()
             .context("failed to execute cargo")?;
 
@@ -326,20 +505,279 @@ crate-type = [\"dylib\"]")
         Ok(())
     }
 
+    fn check_abi(path: &Path) -> Result<(), Error> {
+        // loaded without RTLD_NODELETE so a rebuilt library can be loaded afterwards
+        let lib: Library = ::libloading::os::unix::Library::open(Some(path), libc::RTLD_NOW)
+            .context("failed to load libconfig")?.into();
+
+        let fingerprint: Symbol<extern "C" fn() -> u64> = unsafe { lib.get(b"xr3wm_abi_fingerprint") }
+            .map_err(|_| err_msg("config was built against an xr3wm version without abi check"))?;
+        let version: Symbol<extern "C" fn() -> *const libc::c_char> = unsafe { lib.get(b"xr3wm_version") }
+            .context("failed to get xr3wm_version symbol")?;
+
+        if fingerprint() != abi::fingerprint() {
+            let version = unsafe { CStr::from_ptr(version()) }.to_string_lossy().into_owned();
+            bail!("config was built against xr3wm {} with abi {:x}, expected {} with abi {:x}",
+                  version,
+                  fingerprint(),
+                  env!("CARGO_PKG_VERSION"),
+                  abi::fingerprint());
+        }
+
+        Ok(())
+    }
+
+    // creates the default config.rs on first run
+    fn create_default() -> Result<(), Error> {
+        let path = paths::config_rs();
+        if path.exists() {
+            return Ok(());
+        }
+
+        info!("creating default config {}", path.display());
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)
+                .context("failed to create config directory")?;
+        }
+
+        File::create(&path)
+            .and_then(|mut f| f.write_all(DEFAULT_CONFIG.as_bytes()))
+            .context(format!("failed to create {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Loads `config.toml` if it exists and falls back to compiling and loading `config.rs` otherwise
     pub fn load() -> Result<Config, Error> {
+        if let Some(path) = paths::config_toml() {
+            return Config::load_toml(&path);
+        }
+
         let mut cfg: Config = Default::default();
 
+        Config::create_default()?;
+
         Config::compile()
             .context("failed to compile config")?;
 
-        let lib: Library = ::libloading::os::unix::Library::open(Some(concat!(env!("HOME"), "/.xr3wm/.build/target/debug/libconfig.so")), libc::RTLD_NOW | libc::RTLD_NODELETE)
+        // a stale build directory can contain a config built by another version or compiler
+        if let Err(e) = Config::check_abi(&paths::config_lib()) {
+            warn!("{}, rebuilding config", e);
+
+            Command::new("cargo")
+                .arg("clean")
+                .current_dir(paths::build_dir())
+                .output()
+                .context("failed to execute cargo clean")?;
+            Config::compile()
+                .context("failed to compile config")?;
+            Config::check_abi(&paths::config_lib())
+                .context("config is incompatible even after a rebuild")?;
+        }
+
+        Config::configure(&paths::config_lib(), &mut cfg)?;
+
+        Ok(cfg)
+    }
+
+    fn load_toml(path: &Path) -> Result<Config, Error> {
+        debug!("loading {}", path.display());
+
+        let mut cfg: Config = Default::default();
+        TomlConfig::load(path)?
+            .apply(&mut cfg)
+            .context(format!("failed to apply {}", path.display()))?;
+
+        Ok(cfg)
+    }
+
+    // calls `configure` of the config dylib, which stays loaded as the config refers to its code
+    fn configure(path: &Path, cfg: &mut Config) -> Result<(), Error> {
+        let lib: Library = ::libloading::os::unix::Library::open(Some(path), libc::RTLD_NOW | libc::RTLD_NODELETE)
             .context("failed to load libconfig")?.into();
 
-        let func: Symbol<extern fn(&mut Config)> = unsafe { lib.get(b"configure") }
+        let func: Symbol<extern "C" fn(&mut Config)> = unsafe { lib.get(b"configure") }
             .context("failed to get symbol")?;
 
-        func(&mut cfg);
+        func(cfg);
 
-        Ok(cfg)
+        Ok(())
+    }
+
+    /// Loads the already rebuilt config again to swap it into the running window manager
+    pub fn reload() -> Result<Config, Error> {
+        if let Some(path) = paths::config_toml() {
+            return Config::load_toml(&path);
+        }
+
+        let mut cfg: Config = Default::default();
+
+        // the dynamic loader hands out the library loaded before for a known path,
+        // so every reload loads a copy of its own
+        let path = paths::build_dir().join(format!("libconfig-{}-{}.so",
+                                                   process::id(),
+                                                   RELOADS.fetch_add(1, Ordering::SeqCst)));
+        copy(paths::config_lib(), &path)
+            .context("failed to copy libconfig")?;
+
+        let result = Config::check_abi(&path)
+            .and_then(|_| Config::configure(&path, &mut cfg));
+        remove_file(&path).ok();
+
+        result.map(|_| cfg)
+    }
+
+    /// Checks the config for mistakes that would otherwise only show up at runtime.
+    ///
+    /// Screen indices are only checked if the number of screens is known.
+    pub fn validate(&self, screens: Option<usize>) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        let mut error = |message: String| issues.push(ConfigIssue { severity: Severity::Error, message });
+        let num_ws = self.workspaces.len();
+
+        if self.workspaces.is_empty() {
+            error("no workspaces configured".to_string());
+        }
+
+        for (i, ws) in self.workspaces.iter().enumerate() {
+            if let Err(e) = ws.layout.validate() {
+                error(format!("workspace {} '{}': invalid layout: {}", i + 1, ws.tag, e));
+            }
+        }
+
+        for binding in self.keybindings.iter() {
+            if !is_valid_key(&binding.key) {
+                error(format!("keybinding '{}': unknown key '{}'", binding.cmd, binding.key));
+            }
+
+            match binding.cmd {
+                Cmd::SwitchWorkspace(x) | Cmd::MoveToWorkspace(x) | Cmd::SpawnOn(x, _) if x == 0 || x > num_ws => {
+                    error(format!("keybinding '{}': workspace {} does not exist, there are {} workspaces",
+                                  binding.cmd, x, num_ws));
+                }
+                Cmd::SwitchScreen(x) | Cmd::MoveToScreen(x) if x == 0 || screens.map(|n| x > n).unwrap_or(false) => {
+                    error(format!("keybinding '{}': screen {} does not exist", binding.cmd, x));
+                }
+                _ => {}
+            }
+        }
+
+        for hook in self.manage_hooks.iter() {
+            match hook.cmd {
+                CmdManage::Move(x) if x == 0 || x > num_ws => {
+                    error(format!("manage hook for '{}': workspace {} does not exist, there are {} workspaces",
+                                  hook.class_name, x, num_ws));
+                }
+                ref cmd if !cmd.is_supported() => {
+                    error(format!("manage hook for '{}': only 'move' is supported so far", hook.class_name));
+                }
+                _ => {}
+            }
+        }
+
+        let mut warning = |message: String| issues.push(ConfigIssue { severity: Severity::Warning, message });
+
+        for (i, binding) in self.keybindings.iter().enumerate() {
+            let shadowed = self.keybindings[..i].iter()
+                .find(|x| x.mods == binding.mods && x.key == binding.key);
+
+            if let Some(other) = shadowed {
+                warning(format!("keybinding '{}' and '{}' use the same keys ({:#04x}+{}), both will run",
+                                other.cmd, binding.cmd, binding.mods, binding.key));
+            }
+        }
+
+        if let Some(n) = screens {
+            for (i, ws) in self.workspaces.iter().enumerate() {
+                if ws.screen >= n {
+                    warning(format!("workspace {} '{}': screen {} does not exist, using screen 0 instead",
+                                    i + 1, ws.tag, ws.screen));
+                }
+            }
+        }
+
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issues(config: &Config, screens: Option<usize>) -> Vec<(Severity, String)> {
+        config.validate(screens)
+            .into_iter()
+            .map(|x| (x.severity, x.message))
+            .collect()
+    }
+
+    fn bind(config: &mut Config, mods: u8, key: &str, cmd: Cmd) {
+        config.keybindings.push(Keybinding {
+            mods,
+            key: key.to_string(),
+            cmd,
+        });
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert!(issues(&Config::default(), None).is_empty());
+    }
+
+    #[test]
+    fn workspace_out_of_range() {
+        let mut config = Config::default();
+        bind(&mut config, MOD_CONTROL, "1", Cmd::SwitchWorkspace(10));
+        bind(&mut config, MOD_CONTROL, "2", Cmd::MoveToWorkspace(0));
+
+        assert_eq!(issues(&config, None), vec![
+            (Severity::Error, "keybinding 'switch-workspace 10': workspace 10 does not exist, there are 9 workspaces".to_string()),
+            (Severity::Error, "keybinding 'move-to-workspace 0': workspace 0 does not exist, there are 9 workspaces".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let mut config = Config::default();
+        bind(&mut config, 0, "NoSuchKey", Cmd::KillClient);
+
+        assert_eq!(issues(&config, None), vec![
+            (Severity::Error, "keybinding 'kill-client': unknown key 'NoSuchKey'".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn duplicate_bindings() {
+        let mut config = Config::default();
+        bind(&mut config, MOD_SHIFT, "q", Cmd::Exit);
+        bind(&mut config, MOD_CONTROL, "q", Cmd::Exit);
+
+        let issues = issues(&config, None);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].0, Severity::Warning);
+        assert!(issues[0].1.starts_with("keybinding 'kill-client' and 'exit' use the same keys"), "{}", issues[0].1);
+    }
+
+    #[test]
+    fn screen_out_of_range() {
+        let mut config = Config::default();
+        config.keybindings.retain(|x| !matches!(x.cmd, Cmd::SwitchScreen(_) | Cmd::MoveToScreen(_)));
+        config.workspaces[1].screen = 2;
+
+        assert_eq!(issues(&config, Some(2)), vec![
+            (Severity::Warning, "workspace 2 '2': screen 2 does not exist, using screen 0 instead".to_string()),
+        ]);
+        assert!(issues(&config, Some(3)).is_empty());
+        assert!(issues(&config, None).is_empty());
+    }
+
+    #[test]
+    fn empty_choose_layout() {
+        let mut config = Config::default();
+        config.workspaces[0].layout = ChooseLayout::new(Vec::new());
+
+        assert_eq!(issues(&config, None), vec![
+            (Severity::Error, "workspace 1 '1': invalid layout: ChooseLayout needs at least one layout".to_string()),
+        ]);
     }
 }
@@ -5,10 +5,16 @@ extern crate xlib;
 extern crate xinerama;
 extern crate libc;
 extern crate failure;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
 
 pub mod core {
     pub mod commands {
         pub use ::commands::{Cmd, CmdManage, ManageHook};
+        pub use ::spawn::{Program, Spawn, Startup, StartupPolicy};
     }
 
     pub mod keycode {
@@ -19,13 +25,32 @@ pub mod core {
         pub use ::layout::*;
     }
 
-    pub use ::config::{Config, Statusbar, Keybinding, LogInfo};
+    pub use ::config::{Config, Statusbar, Keybinding, LogInfo, Swallow};
     pub use ::workspaces::WorkspaceConfig;
 }
 
+/// Fingerprint checked by the window manager before calling `configure` of the config dylib
+#[no_mangle]
+pub extern "C" fn xr3wm_abi_fingerprint() -> u64 {
+    abi::fingerprint()
+}
+
+/// Null terminated version of the xr3wm crate the config dylib was built against
+#[no_mangle]
+pub extern "C" fn xr3wm_version() -> *const libc::c_char {
+    abi::VERSION.as_ptr() as *const libc::c_char
+}
+
+mod abi;
+mod paths;
 mod xlib_window_system;
 mod config;
 mod workspaces;
 mod commands;
 mod keycode;
 mod layout;
+mod toml_config;
+mod state;
+mod spawn;
+mod procfs;
+mod source_hash;
@@ -0,0 +1,140 @@
+//! Recovering from panics while starting up and in the event loop by saving the state and
+//! restarting in place, falling back to the default config if xr3wm keeps crashing right after starting.
+
+use std::backtrace::Backtrace;
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use failure::*;
+use commands;
+use config::Config;
+use paths;
+use session::Session;
+use utils;
+use workspaces::Workspaces;
+use xlib_window_system::XlibWindowSystem;
+
+/// Environment variable telling the restarted instance to use the default config
+const SAFE_MODE_ENV: &str = "XR3WM_SAFE_MODE";
+
+/// Crashes within this many seconds count as crash loop
+const CRASH_PERIOD: u64 = 60;
+const MAX_CRASHES: usize = 3;
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Logs panics with their location and a backtrace, the default hook only prints to stderr
+pub fn install_hook() {
+    panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload.downcast_ref::<&str>()
+            .map(|x| x.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        let location = info.location()
+            .map(|x| format!(" at {}:{}", x.file(), x.line()))
+            .unwrap_or_default();
+
+        error!("panic{}: {}\nbacktrace:\n{}", location, message, Backtrace::force_capture());
+    }));
+}
+
+/// Whether xr3wm was restarted with the default config after crashing in a loop.
+///
+/// The flag is removed from the environment, restarting by hand tries the real config again.
+pub fn take_safe_mode() -> bool {
+    let safe_mode = env::var_os(SAFE_MODE_ENV).is_some();
+    env::remove_var(SAFE_MODE_ENV);
+    SAFE_MODE.store(safe_mode, Ordering::SeqCst);
+    safe_mode
+}
+
+// records the crash and returns the number of crashes within the crash period
+fn record_crash() -> usize {
+    let path = paths::crash_file();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    let mut crashes: Vec<u64> = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|x| x.trim().parse::<u64>().ok())
+        .filter(|&x| now.saturating_sub(x) < CRASH_PERIOD)
+        .collect();
+    crashes.push(now);
+
+    let content: Vec<String> = crashes.iter().map(|x| x.to_string()).collect();
+    if let Err(e) = fs::write(&path, content.join("\n")) {
+        warn!("failed to write {}: {}", path.display(), e);
+    }
+
+    crashes.len()
+}
+
+// counts the crash and switches to safe mode for the restart in case of a crash loop,
+// fails if xr3wm keeps crashing even with the default config
+fn check_crash_loop() -> Result<(), Error> {
+    let crashes = record_crash();
+    if crashes >= MAX_CRASHES {
+        if SAFE_MODE.load(Ordering::SeqCst) {
+            bail!("crashed {} times within {} seconds with the default config, giving up", crashes, CRASH_PERIOD);
+        }
+
+        warn!("crashed {} times within {} seconds, restarting with the default config", crashes, CRASH_PERIOD);
+        env::set_var(SAFE_MODE_ENV, "1");
+    }
+
+    Ok(())
+}
+
+fn restart() -> Error {
+    info!("restarting after crash...");
+    match commands::exec_self() {
+        Ok(_) => err_msg("failed to restart after crash"),
+        Err(e) => e,
+    }
+}
+
+/// Restarts xr3wm in place after a panic while starting up, before the event loop took over.
+///
+/// A workspace state not restored yet is left for the restarted instance. Returns only if the
+/// restart failed or xr3wm keeps crashing even with the default config.
+pub fn recover_startup() -> Error {
+    if let Err(e) = check_crash_loop() {
+        return e;
+    }
+
+    restart()
+}
+
+/// Saves the state after a panic in the event loop and restarts xr3wm in place.
+///
+/// Returns only if the restart failed or xr3wm keeps crashing even with the default config.
+pub fn recover(config: &Config, ws: &XlibWindowSystem, workspaces: &Workspaces, session: &mut Session) -> Error {
+    if let Err(e) = check_crash_loop() {
+        return e;
+    }
+
+    // the panic might have left the state inconsistent, failing to save it must not prevent the restart
+    if config.session_restore {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+                session.update(ws, workspaces);
+                session.flush(true);
+            }))
+            .map_err(|_| error!("failed to save session"))
+            .ok();
+    }
+
+    let saved = panic::catch_unwind(AssertUnwindSafe(|| workspaces.save_state(ws)))
+        .map_err(|_| err_msg("failed to collect workspace state"))
+        .and_then(|x| x.save(&paths::workspace_state()));
+    if let Err(e) = saved {
+        error!("restarting without workspace state: {}", utils::concat_error_chain(&e));
+    }
+
+    restart()
+}
@@ -0,0 +1,222 @@
+use workspaces::Workspaces;
+use xlib::Window;
+use xlib_window_system::{XlibWindowSystem, XlibEvent};
+
+/// Names accepted by the ipc `subscribe` request, a `mode` event follows once binding modes exist
+pub const EVENT_KINDS: [&str; 7] = ["workspace", "window", "title", "layout", "urgency", "screen", "reload"];
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowChange {
+    New,
+    Close,
+    Move,
+    Focus,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadChange {
+    Build,
+    Fail,
+    Restart,
+    Done,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Workspace {
+        current: String,
+        previous: String,
+    },
+    Window {
+        change: WindowChange,
+        window: Window,
+        workspace: String,
+    },
+    Title {
+        window: Window,
+        title: String,
+    },
+    Layout {
+        workspace: String,
+        layout: String,
+    },
+    Urgency {
+        window: Window,
+        workspace: String,
+        urgent: bool,
+    },
+    Screen {
+        screens: usize,
+    },
+    Reload {
+        change: ReloadChange,
+        error: Option<String>,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Event::Workspace { .. } => "workspace",
+            Event::Window { .. } => "window",
+            Event::Title { .. } => "title",
+            Event::Layout { .. } => "layout",
+            Event::Urgency { .. } => "urgency",
+            Event::Screen { .. } => "screen",
+            Event::Reload { .. } => "reload",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        ::serde_json::to_string(self)
+            .unwrap_or_else(|_| String::new())
+    }
+
+    /// Events that can not be derived from the workspace state alone
+    pub fn from_xevent(event: &XlibEvent, ws: &XlibWindowSystem, workspaces: &Workspaces) -> Vec<Event> {
+        match *event {
+            XlibEvent::XPropertyNotify(window, atom, _) => {
+                if workspaces.contains(window) &&
+                   (atom == ws.get_atom("WM_NAME") || atom == ws.get_atom("_NET_WM_NAME")) {
+                    vec![Event::Title {
+                        window,
+                        title: ws.get_window_title(window),
+                    }]
+                } else {
+                    Vec::new()
+                }
+            }
+            XlibEvent::XConfigurationNotify(_) => {
+                vec![Event::Screen { screens: ws.get_screen_infos().len() }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Whether handling the event can change the workspace state, most property changes
+/// like window titles and configure requests of clients don't
+pub fn changes_state(event: &XlibEvent, ws: &XlibWindowSystem) -> bool {
+    match *event {
+        XlibEvent::XConfigurationRequest(..) => false,
+        XlibEvent::XPropertyNotify(_, atom, _) => atom == ws.get_atom("WM_HINTS"),
+        _ => true,
+    }
+}
+
+struct WorkspaceState {
+    tag: String,
+    layout: String,
+    windows: Vec<Window>,
+    urgent: Vec<Window>,
+    focused_window: Window,
+}
+
+/// Snapshot of the workspace state used to derive events by comparing it with a later state
+pub struct State {
+    current: usize,
+    workspaces: Vec<WorkspaceState>,
+}
+
+impl State {
+    pub fn new(workspaces: &Workspaces) -> State {
+        State {
+            current: workspaces.get_index(),
+            workspaces: workspaces.all()
+                .iter()
+                .map(|x| {
+                    WorkspaceState {
+                        tag: x.get_tag(),
+                        layout: x.get_layout().name(),
+                        windows: x.all(),
+                        urgent: x.all_urgent(),
+                        focused_window: x.focused_window(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn find_window(&self, window: Window) -> Option<&WorkspaceState> {
+        self.workspaces.iter().find(|x| x.windows.contains(&window))
+    }
+
+    pub fn diff(&self, new: &State) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for ws in new.workspaces.iter() {
+            for &window in ws.windows.iter() {
+                match self.find_window(window) {
+                    None => events.push(Event::Window {
+                        change: WindowChange::New,
+                        window,
+                        workspace: ws.tag.clone(),
+                    }),
+                    Some(prev) if prev.tag != ws.tag => events.push(Event::Window {
+                        change: WindowChange::Move,
+                        window,
+                        workspace: ws.tag.clone(),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+
+        for ws in self.workspaces.iter() {
+            for &window in ws.windows.iter().filter(|&&w| new.find_window(w).is_none()) {
+                events.push(Event::Window {
+                    change: WindowChange::Close,
+                    window,
+                    workspace: ws.tag.clone(),
+                });
+            }
+        }
+
+        for (prev, ws) in self.workspaces.iter().zip(new.workspaces.iter()) {
+            if prev.layout != ws.layout {
+                events.push(Event::Layout {
+                    workspace: ws.tag.clone(),
+                    layout: ws.layout.clone(),
+                });
+            }
+
+            for &window in ws.urgent.iter().filter(|w| !prev.urgent.contains(w)) {
+                events.push(Event::Urgency {
+                    window,
+                    workspace: ws.tag.clone(),
+                    urgent: true,
+                });
+            }
+
+            for &window in prev.urgent.iter().filter(|w| !ws.urgent.contains(w) && ws.windows.contains(w)) {
+                events.push(Event::Urgency {
+                    window,
+                    workspace: ws.tag.clone(),
+                    urgent: false,
+                });
+            }
+        }
+
+        if self.current != new.current {
+            events.push(Event::Workspace {
+                current: new.workspaces[new.current].tag.clone(),
+                previous: self.workspaces[self.current].tag.clone(),
+            });
+        }
+
+        let prev_focus = self.workspaces[self.current].focused_window;
+        let focus = &new.workspaces[new.current];
+        if focus.focused_window != 0 && (self.current != new.current || prev_focus != focus.focused_window) {
+            events.push(Event::Window {
+                change: WindowChange::Focus,
+                window: focus.focused_window,
+                workspace: focus.tag.clone(),
+            });
+        }
+
+        events
+    }
+}
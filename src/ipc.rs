@@ -0,0 +1,376 @@
+use std::env;
+use std::fs::remove_file;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use events::{Event, EVENT_KINDS};
+use failure::*;
+
+/// Name of the environment variable and root window property holding the socket path
+pub const SOCKET_ENV: &str = "XR3WM_SOCKET";
+
+/// Clients not reading their replies and events are dropped once this much output is queued
+const MAX_OUTPUT: usize = 4 * 1024 * 1024;
+
+pub struct IpcClient {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    // replies and events not yet accepted by the socket
+    output: Vec<u8>,
+    subscriptions: Vec<String>,
+    // the client is done sending, it is dropped once its output is written
+    eof: bool,
+    closed: bool,
+}
+
+impl IpcClient {
+    fn new(stream: UnixStream) -> Result<IpcClient, Error> {
+        stream.set_nonblocking(true)
+            .context("failed to set ipc client to non-blocking")?;
+
+        Ok(IpcClient {
+            stream,
+            buffer: Vec::new(),
+            output: Vec::new(),
+            subscriptions: Vec::new(),
+            eof: false,
+            closed: false,
+        })
+    }
+
+    // read all available data and return every complete line
+    fn read_lines(&mut self) -> Vec<String> {
+        let mut chunk = [0u8; 1024];
+
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("failed to read from ipc client: {}", e);
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|&x| x == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            // trailing whitespace can be part of a command line, see `Cmd`
+            lines.push(String::from_utf8_lossy(&line[..pos]).trim_end_matches('\r').to_string());
+        }
+
+        lines.into_iter().filter(|x| !x.trim().is_empty()).collect()
+    }
+
+    /// Queues the message and writes as much of the queue as the socket accepts without blocking,
+    /// the rest is written once the socket becomes writable again
+    pub fn send(&mut self, msg: &str) -> Result<(), Error> {
+        self.output.extend_from_slice(msg.as_bytes());
+        self.output.push(b'\n');
+        self.flush()?;
+
+        if self.output.len() > MAX_OUTPUT {
+            bail!("ipc client is not reading, dropping it with {} bytes queued", self.output.len());
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => bail!("ipc client closed the connection"),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.context("failed to write to ipc client").into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_done(&self) -> bool {
+        self.closed || (self.eof && self.output.is_empty())
+    }
+
+    // handles `subscribe <event>...`, an empty list subscribes to every event
+    fn subscribe(&mut self, args: &str) -> Reply {
+        let kinds: Vec<String> = args.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect();
+
+        if let Some(x) = kinds.iter().find(|x| !EVENT_KINDS.contains(&x.as_str())) {
+            return Reply::err(format!("unknown event '{}'", x));
+        }
+
+        self.subscriptions = if kinds.is_empty() {
+            EVENT_KINDS.iter().map(|x| x.to_string()).collect()
+        } else {
+            kinds
+        };
+
+        Reply::ok()
+    }
+}
+
+pub struct IpcServer {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<IpcClient>,
+}
+
+impl IpcServer {
+    pub fn new(display: &str) -> Result<IpcServer, Error> {
+        let path = IpcServer::socket_path(display);
+
+        if path.exists() {
+            debug!("removing stale ipc socket {}", path.display());
+            remove_file(&path)
+                .context("failed to remove stale ipc socket")?;
+        }
+
+        let listener = UnixListener::bind(&path)
+            .context(format!("failed to bind ipc socket {}", path.display()))?;
+        listener.set_nonblocking(true)
+            .context("failed to set ipc socket to non-blocking")?;
+
+        env::set_var(SOCKET_ENV, &path);
+        info!("listening for ipc connections on {}", path.display());
+
+        Ok(IpcServer {
+            path,
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn socket_path(display: &str) -> PathBuf {
+        let dir = env::var("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("/tmp"));
+        let uid = unsafe { libc::getuid() };
+
+        dir.join(format!("xr3wm-{}{}.sock", uid, display.replace('/', "_")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Blocks until either one of the given file descriptors, like the X connection,
+    /// or one of the ipc sockets becomes readable, a client with queued output becomes
+    /// writable or the timeout expired
+    pub fn wait(&self, extra_fds: &[RawFd], timeout: Option<Duration>) {
+        let mut fds: Vec<libc::pollfd> = extra_fds.iter().cloned()
+            .chain(Some(self.listener.as_raw_fd()))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .chain(self.clients.iter().map(|x| libc::pollfd {
+                fd: x.stream.as_raw_fd(),
+                events: match (x.eof, x.output.is_empty()) {
+                    (false, true) => libc::POLLIN,
+                    (false, false) => libc::POLLIN | libc::POLLOUT,
+                    (true, _) => libc::POLLOUT,
+                },
+                revents: 0,
+            }))
+            .collect();
+
+        // one extra millisecond to not wake up right before the timeout
+        let timeout = timeout.map_or(-1, |x| (x.as_millis() + 1) as libc::c_int);
+
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                error!("failed to poll ipc sockets: {}", err);
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    trace!("new ipc connection");
+                    match IpcClient::new(stream) {
+                        Ok(client) => self.clients.push(client),
+                        Err(e) => error!("{}", e),
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("failed to accept ipc connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Accepts pending connections and passes every received request to the handler,
+    /// sending its return value back to the requesting client.
+    /// Subscription requests are handled by the server itself.
+    pub fn process<F>(&mut self, mut handler: F)
+        where F: FnMut(&str) -> String
+    {
+        self.accept();
+
+        for client in self.clients.iter_mut() {
+            if let Err(e) = client.flush() {
+                debug!("{}", e);
+                client.closed = true;
+                continue;
+            }
+
+            for request in client.read_lines() {
+                trace!("ipc request: {}", request);
+                let trimmed = request.trim();
+                let reply = if trimmed == "subscribe" || trimmed.starts_with("subscribe ") {
+                    client.subscribe(&trimmed["subscribe".len()..]).to_json()
+                } else {
+                    handler(&request)
+                };
+
+                if let Err(e) = client.send(&reply) {
+                    debug!("{}", e);
+                    client.closed = true;
+                }
+            }
+        }
+
+        self.clients.retain(|x| !x.is_done());
+    }
+
+    /// Whether any client subscribed to events, otherwise there is no need to derive them
+    pub fn has_subscribers(&self) -> bool {
+        self.clients.iter().any(|x| !x.subscriptions.is_empty())
+    }
+
+    /// Sends the events to every client subscribed to them
+    pub fn broadcast(&mut self, events: &[Event]) {
+        for event in events.iter() {
+            trace!("ipc event: {}", event.to_json());
+
+            for client in self.clients.iter_mut().filter(|x| x.subscriptions.iter().any(|s| s == event.kind())) {
+                if let Err(e) = client.send(&event.to_json()) {
+                    debug!("{}", e);
+                    client.closed = true;
+                }
+            }
+        }
+
+        self.clients.retain(|x| !x.is_done());
+    }
+}
+
+/// Client side of the ipc socket
+pub struct IpcConnection {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl IpcConnection {
+    pub fn connect() -> Result<IpcConnection, Error> {
+        let path = env::var(SOCKET_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| IpcServer::socket_path(&env::var("DISPLAY").unwrap_or_default()));
+
+        let stream = UnixStream::connect(&path)
+            .context(format!("failed to connect to {}", path.display()))?;
+        let reader = BufReader::new(stream.try_clone()
+            .context("failed to clone ipc stream")?);
+
+        Ok(IpcConnection {
+            stream,
+            reader,
+        })
+    }
+
+    pub fn request(&mut self, request: &str) -> Result<Reply, Error> {
+        self.stream.write_all(format!("{}\n", request).as_bytes())
+            .context("failed to send ipc request")?;
+
+        let line = self.read_line()?
+            .ok_or_else(|| err_msg("connection closed by xr3wm"))?;
+
+        ::serde_json::from_str(&line)
+            .context("failed to parse ipc reply")
+            .map_err(|e| e.into())
+    }
+
+    /// Returns the next line sent by the window manager or None if the connection got closed
+    pub fn read_line(&mut self) -> Result<Option<String>, Error> {
+        let mut line = String::new();
+
+        if self.reader.read_line(&mut line).context("failed to read from ipc socket")? == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(line.trim_end().to_string()))
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        remove_file(&self.path).ok();
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Reply {
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<::serde_json::Value>,
+}
+
+impl Reply {
+    pub fn ok() -> Reply {
+        Reply {
+            success: true,
+            error: None,
+            data: None,
+        }
+    }
+
+    pub fn data<T: ::serde::Serialize>(data: &T) -> Reply {
+        match ::serde_json::to_value(data) {
+            Ok(value) => Reply {
+                success: true,
+                error: None,
+                data: Some(value),
+            },
+            Err(e) => Reply::err(format!("failed to serialize reply: {}", e)),
+        }
+    }
+
+    pub fn err(msg: String) -> Reply {
+        Reply {
+            success: false,
+            error: Some(msg),
+            data: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        ::serde_json::to_string(self)
+            .unwrap_or_else(|_| "{\"success\":false}".to_string())
+    }
+}
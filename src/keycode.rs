@@ -8,3 +8,10 @@ pub const MOD_2: u8 = 1 << 4;
 pub const MOD_3: u8 = 1 << 5;
 pub const MOD_4: u8 = 1 << 6;
 pub const MOD_5: u8 = 1 << 7;
+
+/// Whether `name` is a keysym name X knows about, like "Return" or "a"
+pub fn is_valid_key(name: &str) -> bool {
+    ::std::ffi::CString::new(name)
+        .map(|x| unsafe { ::xlib::XStringToKeysym(x.as_ptr()) } != 0)
+        .unwrap_or(false)
+}
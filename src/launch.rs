@@ -0,0 +1,81 @@
+//! Starting programs onto a chosen workspace.
+//!
+//! The first window mapped by a started program is recognized by the startup notification id
+//! handed over in `DESKTOP_STARTUP_ID`, or by `_NET_WM_PID` belonging to the started process or
+//! one of its descendants, since the command usually runs in a shell.
+
+use std::process;
+use std::time::{Duration, Instant};
+use failure::*;
+use procfs::Procfs;
+use spawn::Spawn;
+use xlib::Window;
+use xlib_window_system::XlibWindowSystem;
+
+/// Programs without a window by then are forgotten, their windows are placed as usual
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Pending {
+    pid: u32,
+    workspace: usize,
+    startup_id: String,
+    started: Instant,
+}
+
+#[derive(Default)]
+pub struct Launcher {
+    pending: Vec<Pending>,
+    procfs: Procfs,
+    count: usize,
+}
+
+impl Launcher {
+    /// Starts the program and remembers to put its first window on the workspace with `index`
+    pub fn spawn(&mut self, spawn: &Spawn, index: usize) -> Result<(), Error> {
+        self.count += 1;
+
+        // the timestamp of the startup id is unknown, `_TIME0` tells applications so
+        let startup_id = format!("xr3wm-{}-{}_TIME0", process::id(), self.count);
+        let pid = spawn.clone()
+            .env("DESKTOP_STARTUP_ID", &startup_id)
+            .spawn()?;
+
+        self.pending.push(Pending {
+            pid,
+            workspace: index,
+            startup_id,
+            started: Instant::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Workspace index a newly mapped window has been started for
+    pub fn place(&mut self, window: Window, ws: &XlibWindowSystem) -> Option<usize> {
+        self.pending.retain(|x| {
+            let expired = x.started.elapsed() > TIMEOUT;
+            if expired {
+                debug!("no window of process {} showed up, placing it as usual", x.pid);
+            }
+            !expired
+        });
+
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let startup_id = ws.get_startup_id(window);
+        let pid = ws.get_window_pid(window);
+
+        let index = self.pending.iter()
+            .position(|x| startup_id.as_ref() == Some(&x.startup_id))
+            .or_else(|| {
+                let pid = pid?;
+                self.pending.iter().position(|x| self.procfs.is_descendant(pid, x.pid))
+            })?;
+
+        let pending = self.pending.remove(index);
+        debug!("window {} belongs to process {}, moving it to workspace {}", window, pending.pid, pending.workspace + 1);
+        Some(pending.workspace)
+    }
+}
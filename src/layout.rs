@@ -2,6 +2,8 @@
 #![allow(clippy::new_ret_no_self)]
 use std::cmp::min;
 use std::fmt;
+use std::str::FromStr;
+use failure::*;
 use workspaces::Stack;
 use xlib_window_system::XlibWindowSystem;
 use xlib::Window;
@@ -25,7 +27,18 @@ impl fmt::Debug for Rect {
     }
 }
 
-#[derive(Clone)]
+/// Messages sent to the layout of the current workspace.
+///
+/// Textual form:
+///
+/// ```text
+/// increase | decrease
+/// increase-master | decrease-master
+/// split-horizontal | split-vertical
+/// next-layout | prev-layout | first-layout | last-layout   `next`, `prev`, `first`, `last` are accepted as alias
+/// custom <text>                 the text after the separating space is taken as it is
+/// ```
+#[derive(Clone, PartialEq)]
 pub enum LayoutMsg {
     Increase,
     Decrease,
@@ -58,21 +71,115 @@ impl fmt::Debug for LayoutMsg {
     }
 }
 
+impl FromStr for LayoutMsg {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LayoutMsg, Error> {
+        let s = s.trim_start();
+        let (name, raw) = match s.find(char::is_whitespace) {
+            Some(pos) => (&s[..pos], &s[pos..]),
+            None => (s, ""),
+        };
+
+        // the text of custom messages keeps its whitespace, only the separator is dropped
+        if name == "custom" {
+            let mut chars = raw.chars();
+            if chars.next().is_none() {
+                bail!("'custom' expects a message");
+            }
+            return Ok(LayoutMsg::Custom(chars.as_str().to_string()));
+        }
+
+        let arg = raw.trim();
+        if !arg.is_empty() {
+            bail!("'{}' takes no arguments, got '{}'", name, arg);
+        }
+
+        Ok(match name {
+            "increase" => LayoutMsg::Increase,
+            "decrease" => LayoutMsg::Decrease,
+            "increase-master" => LayoutMsg::IncreaseMaster,
+            "decrease-master" => LayoutMsg::DecreaseMaster,
+            "split-horizontal" => LayoutMsg::SplitHorizontal,
+            "split-vertical" => LayoutMsg::SplitVertical,
+            "next-layout" | "next" => LayoutMsg::NextLayout,
+            "prev-layout" | "prev" => LayoutMsg::PrevLayout,
+            "first-layout" | "first" => LayoutMsg::FirstLayout,
+            "last-layout" | "last" => LayoutMsg::LastLayout,
+            "" => bail!("missing layout message"),
+            x => bail!("unknown layout message '{}'", x),
+        })
+    }
+}
+
+impl fmt::Display for LayoutMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LayoutMsg::Increase => write!(f, "increase"),
+            LayoutMsg::Decrease => write!(f, "decrease"),
+            LayoutMsg::IncreaseMaster => write!(f, "increase-master"),
+            LayoutMsg::DecreaseMaster => write!(f, "decrease-master"),
+            LayoutMsg::SplitHorizontal => write!(f, "split-horizontal"),
+            LayoutMsg::SplitVertical => write!(f, "split-vertical"),
+            LayoutMsg::NextLayout => write!(f, "next-layout"),
+            LayoutMsg::PrevLayout => write!(f, "prev-layout"),
+            LayoutMsg::FirstLayout => write!(f, "first-layout"),
+            LayoutMsg::LastLayout => write!(f, "last-layout"),
+            LayoutMsg::Custom(ref val) => write!(f, "custom {}", val),
+        }
+    }
+}
+
+/// Runtime state of a layout, saved across restarts of the window manager.
+///
+/// Modifiers like `StrutLayout` have no state of their own and pass on the state of the
+/// layout they wrap.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LayoutState {
+    Tall {
+        num_masters: usize,
+        ratio: f32,
+    },
+    Choose {
+        current: usize,
+        layouts: Vec<Option<LayoutState>>,
+    },
+}
+
 pub trait Layout {
     fn name(&self) -> String;
-    fn send_msg(&mut self, LayoutMsg);
+    fn send_msg(&mut self, msg: LayoutMsg);
 
     fn apply(&self, area: Rect, _: &XlibWindowSystem, stack: &Stack) -> Vec<Rect> {
         self.simple_apply(area, &stack.visible)
     }
 
-    fn simple_apply(&self, Rect, &[Window]) -> Vec<Rect> {
+    fn simple_apply(&self, _: Rect, _: &[Window]) -> Vec<Rect> {
         Vec::new()
     }
 
     fn copy<'a>(&self) -> Box<dyn Layout + 'a> {
         panic!("")
     }
+
+    /// Checks the layout parameters, called on config load
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Layout including its parameters, used to find layouts changed by a config reload
+    fn describe(&self) -> String {
+        self.name()
+    }
+
+    /// State changed by layout messages, `None` for layouts without state
+    fn export_state(&self) -> Option<LayoutState> {
+        None
+    }
+
+    /// Restores an exported state, states of another kind of layout are ignored
+    fn import_state(&mut self, _: &LayoutState) {}
 }
 
 pub struct ChooseLayout<'a> {
@@ -81,12 +188,8 @@ pub struct ChooseLayout<'a> {
 }
 
 impl<'a> ChooseLayout<'a> {
+    /// An empty list of layouts is reported by `validate`
     pub fn new(layouts: Vec<Box<dyn Layout + 'a>>) -> Box<dyn Layout + 'a> {
-        // add proper error handling
-        if layouts.is_empty() {
-            panic!("ChooseLayout needs at least one layout");
-        }
-
         Box::new(ChooseLayout {
             layouts,
             current: 0,
@@ -128,6 +231,41 @@ impl<'a> Layout for ChooseLayout<'a> {
     fn copy<'b>(&self) -> Box<dyn Layout + 'b> {
         ChooseLayout::new(self.layouts.iter().map(|x| x.copy()).collect())
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.layouts.is_empty() {
+            bail!("ChooseLayout needs at least one layout");
+        }
+
+        self.layouts.iter().try_for_each(|x| x.validate())
+    }
+
+    fn describe(&self) -> String {
+        format!("Choose({})", self.layouts.iter().map(|x| x.describe()).collect::<Vec<String>>().join(", "))
+    }
+
+    fn export_state(&self) -> Option<LayoutState> {
+        Some(LayoutState::Choose {
+            current: self.current,
+            layouts: self.layouts.iter().map(|x| x.export_state()).collect(),
+        })
+    }
+
+    fn import_state(&mut self, state: &LayoutState) {
+        if let LayoutState::Choose { current, ref layouts } = *state {
+            // the list of layouts might have changed in the config
+            if layouts.len() != self.layouts.len() || current >= self.layouts.len() {
+                return;
+            }
+
+            self.current = current;
+            for (layout, state) in self.layouts.iter_mut().zip(layouts.iter()) {
+                if let Some(ref state) = *state {
+                    layout.import_state(state);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -154,22 +292,14 @@ impl Layout for TallLayout {
 
     fn send_msg(&mut self, msg: LayoutMsg) {
         match msg {
-            LayoutMsg::Increase => {
-                if self.ratio + self.ratio_increment < 1.0 {
-                    self.ratio += self.ratio_increment;
-                }
+            LayoutMsg::Increase if self.ratio + self.ratio_increment < 1.0 => {
+                self.ratio += self.ratio_increment;
             }
-            LayoutMsg::Decrease => {
-                if self.ratio - self.ratio_increment > self.ratio_increment {
-                    self.ratio -= self.ratio_increment;
-                }
+            LayoutMsg::Decrease if self.ratio - self.ratio_increment > self.ratio_increment => {
+                self.ratio -= self.ratio_increment;
             }
             LayoutMsg::IncreaseMaster => self.num_masters += 1,
-            LayoutMsg::DecreaseMaster => {
-                if self.num_masters > 1 {
-                    self.num_masters -= 1;
-                }
-            }
+            LayoutMsg::DecreaseMaster if self.num_masters > 1 => self.num_masters -= 1,
             _ => {}
         }
     }
@@ -209,6 +339,38 @@ impl Layout for TallLayout {
     fn copy<'b>(&self) -> Box<dyn Layout + 'b> {
         Box::new(*self)
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.num_masters == 0 {
+            bail!("TallLayout needs at least one master window");
+        }
+
+        if self.ratio <= 0.0 || self.ratio >= 1.0 {
+            bail!("TallLayout ratio {} is not between 0 and 1", self.ratio);
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("Tall({}, {}, {})", self.num_masters, self.ratio, self.ratio_increment)
+    }
+
+    fn export_state(&self) -> Option<LayoutState> {
+        Some(LayoutState::Tall {
+            num_masters: self.num_masters,
+            ratio: self.ratio,
+        })
+    }
+
+    fn import_state(&mut self, state: &LayoutState) {
+        if let LayoutState::Tall { num_masters, ratio } = *state {
+            if num_masters > 0 && ratio > 0.0 && ratio < 1.0 {
+                self.num_masters = num_masters;
+                self.ratio = ratio;
+            }
+        }
+    }
 }
 
 pub struct StrutLayout<'a> {
@@ -250,6 +412,22 @@ impl<'a> Layout for StrutLayout<'a> {
     fn copy<'b>(&self) -> Box<dyn Layout + 'b> {
         StrutLayout::new(self.layout.copy())
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.layout.validate()
+    }
+
+    fn describe(&self) -> String {
+        format!("Strut({})", self.layout.describe())
+    }
+
+    fn export_state(&self) -> Option<LayoutState> {
+        self.layout.export_state()
+    }
+
+    fn import_state(&mut self, state: &LayoutState) {
+        self.layout.import_state(state);
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -341,6 +519,22 @@ impl<'a> Layout for GapLayout<'a> {
     fn copy<'b>(&self) -> Box<dyn Layout + 'b> {
         GapLayout::new(self.screen_gap, self.window_gap, self.layout.copy())
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.layout.validate()
+    }
+
+    fn describe(&self) -> String {
+        format!("Gap({}, {}, {})", self.screen_gap, self.window_gap, self.layout.describe())
+    }
+
+    fn export_state(&self) -> Option<LayoutState> {
+        self.layout.export_state()
+    }
+
+    fn import_state(&mut self, state: &LayoutState) {
+        self.layout.import_state(state);
+    }
 }
 
 pub struct MirrorLayout<'a> {
@@ -375,4 +569,56 @@ impl<'a> Layout for MirrorLayout<'a> {
     fn copy<'b>(&self) -> Box<dyn Layout + 'b> {
         MirrorLayout::new(self.layout.copy())
     }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.layout.validate()
+    }
+
+    fn describe(&self) -> String {
+        format!("Mirror({})", self.layout.describe())
+    }
+
+    fn export_state(&self) -> Option<LayoutState> {
+        self.layout.export_state()
+    }
+
+    fn import_state(&mut self, state: &LayoutState) {
+        self.layout.import_state(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_msg_round_trip() {
+        let msgs = vec![
+            LayoutMsg::Increase,
+            LayoutMsg::Decrease,
+            LayoutMsg::IncreaseMaster,
+            LayoutMsg::DecreaseMaster,
+            LayoutMsg::SplitHorizontal,
+            LayoutMsg::SplitVertical,
+            LayoutMsg::NextLayout,
+            LayoutMsg::PrevLayout,
+            LayoutMsg::FirstLayout,
+            LayoutMsg::LastLayout,
+            LayoutMsg::Custom("grow 10".to_string()),
+            LayoutMsg::Custom(" x".to_string()),
+            LayoutMsg::Custom("x ".to_string()),
+            LayoutMsg::Custom(String::new()),
+        ];
+
+        for msg in msgs {
+            assert_eq!(msg.to_string().parse::<LayoutMsg>().unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn layout_msg_aliases() {
+        assert_eq!("next".parse::<LayoutMsg>().unwrap(), LayoutMsg::NextLayout);
+        assert_eq!(" last ".parse::<LayoutMsg>().unwrap(), LayoutMsg::LastLayout);
+        assert!("next now".parse::<LayoutMsg>().is_err());
+    }
 }
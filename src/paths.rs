@@ -0,0 +1,146 @@
+//! Runtime resolution of all files used by xr3wm, following the XDG base directory specification.
+//!
+//! | file            | default location                          |
+//! |-----------------|-------------------------------------------|
+//! | config          | `$XDG_CONFIG_HOME/xr3wm/config.{toml,rs}` |
+//! | config build    | `$XDG_CACHE_HOME/xr3wm/build`             |
+//! | log             | `$XDG_STATE_HOME/xr3wm/xr3wm.log`         |
+//! | workspace state | `$XDG_STATE_HOME/xr3wm/workspaces`        |
+//! | session         | `$XDG_STATE_HOME/xr3wm/session`           |
+//! | crash times     | `$XDG_STATE_HOME/xr3wm/crashes`           |
+//!
+//! The legacy `~/.xr3wm` directory is used for the config if it exists and the XDG one doesn't.
+//! Workspace state left in `~/.xr3wm/.tmp` by an older build restarting into this one is picked up.
+//! The config file can be overriden with `--config` which sets `XR3WM_CONFIG`.
+
+#![allow(dead_code)]
+
+use std::env;
+use std::ffi::{CStr, OsStr};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use failure::*;
+
+/// Environment variable holding the config file passed with `--config`
+pub const CONFIG_ENV: &str = "XR3WM_CONFIG";
+
+pub fn home_dir() -> PathBuf {
+    if let Some(home) = env::var_os("HOME").filter(|x| !x.is_empty()) {
+        return PathBuf::from(home);
+    }
+
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if !passwd.is_null() && !(*passwd).pw_dir.is_null() {
+            return PathBuf::from(CStr::from_ptr((*passwd).pw_dir).to_string_lossy().into_owned());
+        }
+    }
+
+    PathBuf::from("/")
+}
+
+// absolute value of an XDG variable or the given directory relative to home
+fn xdg_dir(var: &str, default: &str) -> PathBuf {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|x| x.is_absolute())
+        .unwrap_or_else(|| home_dir().join(default))
+}
+
+fn legacy_dir() -> PathBuf {
+    home_dir().join(".xr3wm")
+}
+
+pub fn config_dir() -> PathBuf {
+    if let Some(path) = config_override() {
+        return path.parent()
+            .map(|x| x.to_path_buf())
+            .unwrap_or_else(|| PathBuf::from("."));
+    }
+
+    let dir = xdg_dir("XDG_CONFIG_HOME", ".config").join("xr3wm");
+    if !dir.exists() && legacy_dir().exists() {
+        legacy_dir()
+    } else {
+        dir
+    }
+}
+
+fn config_override() -> Option<PathBuf> {
+    env::var_os(CONFIG_ENV)
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("toml"))
+}
+
+pub fn config_toml() -> Option<PathBuf> {
+    match config_override() {
+        Some(path) => Some(path).filter(|x| is_toml(x)),
+        None => Some(config_dir().join("config.toml")).filter(|x| x.exists()),
+    }
+}
+
+pub fn config_rs() -> PathBuf {
+    config_override()
+        .filter(|x| !is_toml(x))
+        .unwrap_or_else(|| config_dir().join("config.rs"))
+}
+
+pub fn build_dir() -> PathBuf {
+    xdg_dir("XDG_CACHE_HOME", ".cache").join("xr3wm").join("build")
+}
+
+pub fn config_lib() -> PathBuf {
+    build_dir().join("target").join("debug").join("libconfig.so")
+}
+
+pub fn vendored_source_dir() -> PathBuf {
+    config_dir().join("xr3wm")
+}
+
+pub fn state_dir() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state").join("xr3wm")
+}
+
+pub fn log_file() -> PathBuf {
+    state_dir().join("xr3wm.log")
+}
+
+pub fn workspace_state() -> PathBuf {
+    state_dir().join("workspaces")
+}
+
+/// Workspace state written by builds before the XDG directories were used
+pub fn legacy_workspace_state() -> PathBuf {
+    legacy_dir().join(".tmp")
+}
+
+pub fn session_file() -> PathBuf {
+    state_dir().join("session")
+}
+
+pub fn crash_file() -> PathBuf {
+    state_dir().join("crashes")
+}
+
+/// Replaces a file by writing a temporary file next to it first, so a crash never leaves it half
+/// written. Missing parent directories are created.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .context(format!("failed to create {}", dir.display()))?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(content).and_then(|_| f.sync_all()))
+        .context(format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .context(format!("failed to move {} to {}", tmp.display(), path.display()))?;
+
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Reading the process tree from `/proc`.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Programs recognized as shell when looking for the working directory of a terminal
+const SHELLS: &[&str] = &["sh", "bash", "zsh", "fish", "dash", "ksh", "mksh", "tcsh", "csh", "nu", "elvish", "xonsh"];
+
+/// Access to a proc filesystem, the root can be changed to read a snapshot instead
+pub struct Procfs {
+    root: PathBuf,
+}
+
+struct Stat {
+    name: String,
+    parent: u32,
+    start_time: u64,
+}
+
+impl Default for Procfs {
+    fn default() -> Procfs {
+        Procfs::new("/proc")
+    }
+}
+
+impl Procfs {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Procfs {
+        Procfs {
+            root: root.into(),
+        }
+    }
+
+    fn stat(&self, pid: u32) -> Option<Stat> {
+        let stat = fs::read_to_string(self.root.join(pid.to_string()).join("stat")).ok()?;
+
+        // the command name in parentheses may contain spaces, the fields after it don't
+        let open = stat.find('(')?;
+        let close = stat.rfind(')')?;
+        let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+
+        Some(Stat {
+            name: stat[open + 1..close].to_string(),
+            parent: fields.get(1)?.parse().ok()?,
+            start_time: fields.get(19)?.parse().ok()?,
+        })
+    }
+
+    /// Parent of a process, `None` if it doesn't exist anymore
+    pub fn parent(&self, pid: u32) -> Option<u32> {
+        self.stat(pid).map(|x| x.parent)
+    }
+
+    /// Whether `pid` is `ancestor` itself or one of its descendants
+    #[allow(dead_code)] // only used by the binary, for placing launched programs and swallowing
+    pub fn is_descendant(&self, pid: u32, ancestor: u32) -> bool {
+        let mut current = pid;
+
+        // the depth limit guards against loops in a broken snapshot
+        for _ in 0..64 {
+            if current == ancestor {
+                return true;
+            }
+
+            match self.parent(current) {
+                Some(x) if x > 1 && x != current => current = x,
+                _ => return false,
+            }
+        }
+
+        false
+    }
+
+    // the most recently started child of a process
+    fn youngest_child(&self, pid: u32) -> Option<u32> {
+        fs::read_dir(&self.root).ok()?
+            .filter_map(|x| x.ok())
+            .filter_map(|x| x.file_name().to_str().and_then(|x| x.parse::<u32>().ok()))
+            .filter_map(|x| self.stat(x).map(|stat| (x, stat)))
+            .filter(|(_, stat)| stat.parent == pid)
+            .max_by_key(|(_, stat)| stat.start_time)
+            .map(|(x, _)| x)
+    }
+
+    /// Working directory of the deepest shell below a process, e.g. the shell running in a
+    /// terminal. Falls back to the deepest process if there is no shell.
+    ///
+    /// The youngest child is followed on every level, the newest tab or split wins.
+    pub fn shell_cwd(&self, pid: u32) -> Option<PathBuf> {
+        let mut current = pid;
+        let mut shell = None;
+
+        // bounded like `is_descendant`
+        for _ in 0..64 {
+            if self.stat(current).map(|x| SHELLS.contains(&x.name.as_str())).unwrap_or(false) {
+                shell = Some(current);
+            }
+
+            match self.youngest_child(current) {
+                Some(x) => current = x,
+                None => break,
+            }
+        }
+
+        let pid = shell.unwrap_or(current);
+        fs::read_link(self.root.join(pid.to_string()).join("cwd")).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    // fake proc tree in a temp dir, removed when dropped
+    struct FakeProc {
+        root: PathBuf,
+    }
+
+    impl FakeProc {
+        fn new(name: &str) -> FakeProc {
+            let root = env::temp_dir().join(format!("xr3wm-procfs-{}-{}", process::id(), name));
+            fs::remove_dir_all(&root).ok();
+            fs::create_dir_all(&root).unwrap();
+            FakeProc {
+                root,
+            }
+        }
+
+        fn add(&self, pid: u32, name: &str, parent: u32, start_time: u64) -> &FakeProc {
+            let dir = self.root.join(pid.to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let zeros = vec!["0"; 17].join(" ");
+            fs::write(dir.join("stat"), format!("{} ({}) S {} {} {}\n", pid, name, parent, zeros, start_time)).unwrap();
+            symlink(format!("/home/{}", pid), dir.join("cwd")).unwrap();
+            self
+        }
+
+        fn shell_cwd(&self, pid: u32) -> Option<PathBuf> {
+            Procfs::new(self.root.clone()).shell_cwd(pid)
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.root).ok();
+        }
+    }
+
+    #[test]
+    fn deepest_shell() {
+        let proc = FakeProc::new("deepest");
+        proc.add(10, "urxvt", 1, 100)
+            .add(11, "zsh", 10, 101)
+            .add(12, "bash", 11, 102)
+            .add(13, "vim", 12, 103);
+
+        assert_eq!(proc.shell_cwd(10), Some(PathBuf::from("/home/12")));
+    }
+
+    #[test]
+    fn youngest_child() {
+        let proc = FakeProc::new("youngest");
+        proc.add(10, "tmux: server", 1, 100)
+            .add(11, "bash", 10, 105)
+            .add(12, "bash", 10, 110)
+            .add(13, "bash", 10, 103);
+
+        assert_eq!(proc.shell_cwd(10), Some(PathBuf::from("/home/12")));
+    }
+
+    #[test]
+    fn no_shell() {
+        let proc = FakeProc::new("no-shell");
+        proc.add(10, "emacs", 1, 100)
+            .add(11, "aspell", 10, 101);
+
+        assert_eq!(proc.shell_cwd(10), Some(PathBuf::from("/home/11")));
+        assert_eq!(proc.shell_cwd(20), None);
+    }
+
+    #[test]
+    fn comm_with_spaces_and_parentheses() {
+        let proc = FakeProc::new("comm");
+        proc.add(10, "Web Content", 1, 100)
+            .add(11, "a) (b", 10, 101)
+            .add(12, "sh", 11, 102);
+
+        assert_eq!(Procfs::new(proc.root.clone()).parent(11), Some(10));
+        assert_eq!(proc.shell_cwd(10), Some(PathBuf::from("/home/12")));
+    }
+
+    #[test]
+    fn loop_in_snapshot() {
+        let proc = FakeProc::new("loop");
+        proc.add(10, "urxvt", 11, 100)
+            .add(11, "bash", 10, 101);
+
+        assert_eq!(proc.shell_cwd(10), Some(PathBuf::from("/home/11")));
+        assert!(!Procfs::new(proc.root.clone()).is_descendant(10, 20));
+    }
+}
@@ -0,0 +1,291 @@
+//! Rebuilding the config in the background while the event loop keeps handling events
+//! and swapping it into the running window manager afterwards.
+
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{Child, ChildStderr, Stdio};
+use std::time::{Duration, Instant};
+use failure::*;
+use config::{Config, Severity};
+use events::{Event, ReloadChange};
+use toml_config::TomlConfig;
+use paths;
+use spawn;
+use utils;
+use workspaces::Workspaces;
+use xlib_window_system::XlibWindowSystem;
+
+/// Failed reloads are shown in the statusbar for this long
+const FAIL_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Build {
+    child: Child,
+    stderr: ChildStderr,
+    output: Vec<u8>,
+}
+
+// logs the warnings of the config validation and fails on errors
+fn check(config: &Config, screens: Option<usize>) -> Result<(), Error> {
+    let issues = config.validate(screens);
+    for issue in issues.iter().filter(|x| x.severity == Severity::Warning) {
+        warn!("config: {}", issue.message);
+    }
+
+    let errors: Vec<String> = issues.iter()
+        .filter(|x| x.severity == Severity::Error)
+        .map(|x| x.to_string())
+        .collect();
+    if !errors.is_empty() {
+        bail!("invalid config:\n{}", errors.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Drives a reload from rebuilding the config up to swapping it into the running window manager.
+///
+/// Progress is reported as ipc events and as a statusbar message, build failures are
+/// additionally shown with xmessage while the current session keeps running.
+#[derive(Default)]
+pub struct Reloader {
+    build: Option<Build>,
+    ready: bool,
+    restart: bool,
+    message: Option<String>,
+    // when the message of a failed reload is cleared
+    message_expires: Option<Instant>,
+    events: Vec<Event>,
+}
+
+impl Reloader {
+    /// File descriptor becoming readable while the build makes progress
+    pub fn fd(&self) -> Option<RawFd> {
+        self.build.as_ref().map(|x| x.stderr.as_raw_fd())
+    }
+
+    /// Message for the statusbar describing the last reload
+    pub fn message(&self) -> Option<String> {
+        self.message.clone()
+    }
+
+    fn set_message(&mut self, message: Option<&str>) {
+        self.message = message.map(|x| x.to_string());
+        self.message_expires = None;
+    }
+
+    /// Time until the message of a failed reload expires
+    pub fn timeout(&self) -> Option<Duration> {
+        self.message_expires.map(|x| x.saturating_duration_since(Instant::now()))
+    }
+
+    /// Clears the message of a failed reload once it expired, returns whether it did
+    pub fn expire(&mut self) -> bool {
+        match self.message_expires {
+            Some(x) if x <= Instant::now() => {
+                self.set_message(None);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        mem::take(&mut self.events)
+    }
+
+    /// Whether the new config is ready and the window manager should restart, resets the flag
+    pub fn take_ready(&mut self) -> bool {
+        mem::take(&mut self.ready)
+    }
+
+    /// Starts rebuilding the config, `restart` restarts the window manager afterwards
+    /// instead of swapping in the new config.
+    ///
+    /// `config.toml` has nothing to build, it is checked right away instead.
+    pub fn start(&mut self, restart: bool) {
+        if self.build.is_some() {
+            info!("the config is already being rebuilt");
+            return;
+        }
+
+        self.restart = restart;
+
+        match self.spawn() {
+            Ok(true) => {
+                self.set_message(Some("rebuilding config..."));
+                self.events.push(Event::Reload {
+                    change: ReloadChange::Build,
+                    error: None,
+                });
+            }
+            Ok(false) => self.succeed(),
+            Err(e) => self.fail(&e),
+        }
+    }
+
+    // returns false if there is no build to wait for
+    fn spawn(&mut self) -> Result<bool, Error> {
+        if let Some(path) = paths::config_toml() {
+            info!("checking {}...", path.display());
+
+            // don't reload a broken config
+            let mut config = Config::default();
+            TomlConfig::load(&path)?
+                .apply(&mut config)
+                .context(format!("failed to apply {}", path.display()))?;
+
+            check(&config, None)?;
+
+            return Ok(false);
+        }
+
+        info!("recompiling in the background...");
+
+        let mut child = Config::build_command()?
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("failed to execute cargo")?;
+
+        let stderr = child.stderr.take()
+            .ok_or_else(|| err_msg("failed to get cargo stderr"))?;
+
+        unsafe {
+            let fd = stderr.as_raw_fd();
+            libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK);
+        }
+
+        spawn::own(child.id());
+        self.build = Some(Build {
+            child,
+            stderr,
+            output: Vec::new(),
+        });
+
+        Ok(true)
+    }
+
+    /// Collects the output of the running build without blocking and finishes the reload
+    /// once cargo exits.
+    pub fn poll(&mut self) {
+        let finished = match self.build {
+            Some(ref mut build) => {
+                let mut buf = [0u8; 4096];
+                loop {
+                    match build.stderr.read(&mut buf) {
+                        Ok(0) => break true,
+                        Ok(n) => build.output.extend_from_slice(&buf[..n]),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => {
+                            error!("failed to read cargo output: {}", e);
+                            break true;
+                        }
+                    }
+                }
+            }
+            None => false,
+        };
+
+        if !finished {
+            return;
+        }
+
+        if let Some(mut build) = self.build.take() {
+            let output = String::from_utf8_lossy(&build.output).into_owned();
+
+            let status = build.child.wait();
+            spawn::release(build.child.id());
+
+            match status {
+                Ok(ref status) if status.success() => self.succeed(),
+                Ok(status) => self.fail(&format_err!("cargo build failed with {}:\n{}", status, output.trim_end())),
+                Err(e) => self.fail(&e.context("failed to wait for cargo").into()),
+            }
+        }
+    }
+
+    fn succeed(&mut self) {
+        info!("config is ready");
+        self.ready = true;
+    }
+
+    /// Swaps the rebuilt config into the running window manager.
+    ///
+    /// Returns true if a restart was requested, the window manager has to restart instead.
+    /// The new config is loaded and validated either way, a restart into a config failing
+    /// the abi check or the validation would end the session.
+    pub fn swap(&mut self, config: &mut Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces) -> bool {
+        let new = Config::reload()
+            .context("failed to load the new config")
+            .map_err(|e| e.into())
+            .and_then(|x| check(&x, Some(ws.get_screen_infos().len())).map(|_| x));
+
+        let mut new = match new {
+            Ok(x) => x,
+            Err(e) => {
+                self.fail(&e);
+                return false;
+            }
+        };
+
+        if self.restart {
+            self.set_message(Some("restarting..."));
+            self.events.push(Event::Reload {
+                change: ReloadChange::Restart,
+                error: None,
+            });
+            return true;
+        }
+
+        if new.mod_key != config.mod_key {
+            ws.ungrab_modifier(config.mod_key);
+            ws.grab_modifier(new.mod_key);
+        }
+
+        workspaces.reconfigure(ws, config, &new);
+
+        let result = match (config.statusbar.as_mut(), new.statusbar.as_mut()) {
+            (Some(old), Some(statusbar)) => statusbar.replace(old),
+            (Some(old), None) => {
+                old.stop();
+                Ok(())
+            }
+            (None, Some(statusbar)) => statusbar.start(),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("{}", e.context("failed to start statusbar"));
+        }
+
+        *config = new;
+
+        info!("config reloaded");
+        self.set_message(None);
+        self.events.push(Event::Reload {
+            change: ReloadChange::Done,
+            error: None,
+        });
+
+        false
+    }
+
+    /// Reports a failed reload, the current session keeps running
+    pub fn fail(&mut self, err: &Error) {
+        let error = utils::concat_error_chain(err);
+        error!("failed to reload: {}", error);
+
+        utils::xmessage(&format!("failed to reload config:\n{}", error))
+            .map_err(|e| warn!("failed to run xmessage: {}", e))
+            .ok();
+
+        self.set_message(Some("config reload failed"));
+        self.message_expires = Some(Instant::now() + FAIL_MESSAGE_TIMEOUT);
+        self.events.push(Event::Reload {
+            change: ReloadChange::Fail,
+            error: Some(error),
+        });
+    }
+}
@@ -0,0 +1,343 @@
+//! Opt-in session file remembering which applications lived on which workspace, so they end up
+//! in the same place and stack position when they are started again after logging back in.
+//!
+//! Windows are matched by class, instance and `WM_WINDOW_ROLE`. The title only decides between
+//! several candidates since most applications change it while running. It is saved as glob
+//! pattern matching the title exactly, editing the session file to e.g. `* - Mozilla Firefox`
+//! loosens it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use failure::*;
+use config::Config;
+use paths;
+use utils;
+use workspaces::Workspaces;
+use xlib::Window;
+use xlib_window_system::XlibWindowSystem;
+
+/// Version of the session format written by this build
+pub const VERSION: u32 = 1;
+
+/// Saved windows that haven't shown up by then are dropped, later windows are placed as usual
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Changes are written once the windows stayed the same for this long
+const WRITE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionWindow {
+    /// Tag of the workspace
+    pub workspace: String,
+    /// Index in the stack of the workspace, managed windows first
+    pub position: usize,
+    pub class: String,
+    pub instance: String,
+    #[serde(default)]
+    pub role: Option<String>,
+    /// Glob pattern of the title, `*` and `?` are wildcards and `\` escapes them
+    #[serde(default)]
+    pub title: String,
+}
+
+impl SessionWindow {
+    fn same_app(&self, other: &SessionWindow) -> bool {
+        self.workspace == other.workspace && self.class == other.class &&
+            self.instance == other.instance && self.role == other.role
+    }
+}
+
+// escapes the wildcards of a title so the pattern only matches the title itself
+fn escape(title: &str) -> String {
+    let mut pattern = String::with_capacity(title.len());
+    for c in title.chars() {
+        if c == '*' || c == '?' || c == '\\' {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+// glob matching with `*` for any and `?` for a single character
+fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('\\') if pattern.get(p + 1) == Some(&text[t]) => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            Some(&c) if c != '\\' && (c == '?' || c == text[t]) => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match star {
+            Some((sp, st)) => {
+                star = Some((sp, st + 1));
+                p = sp + 1;
+                t = st + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    version: u32,
+    windows: Vec<SessionWindow>,
+}
+
+// properties of a window, read once when it is first seen
+#[derive(Clone)]
+struct Identity {
+    class: String,
+    instance: String,
+    role: Option<String>,
+    title: String,
+}
+
+impl Identity {
+    fn new(ws: &XlibWindowSystem, window: Window) -> Identity {
+        Identity {
+            class: ws.get_class_name(window),
+            instance: ws.get_instance_name(window),
+            role: ws.get_window_role(window),
+            title: ws.get_window_title(window),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Session {
+    path: PathBuf,
+    pending: Vec<SessionWindow>,
+    placed: Vec<(Window, SessionWindow)>,
+    started: Option<Instant>,
+    saved: Vec<SessionWindow>,
+    // changed windows not written yet and when they changed first
+    unsaved: Option<(Vec<SessionWindow>, Instant)>,
+    known: HashMap<Window, Identity>,
+}
+
+impl Session {
+    /// Empty session saved to `path`
+    pub fn new(path: &Path) -> Session {
+        Session {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads the session file, `restore` enables placing the saved windows once they appear.
+    ///
+    /// A missing file is an empty session.
+    pub fn load(path: &Path, restore: bool) -> Result<Session, Error> {
+        let mut session = Session::new(path);
+
+        if !path.exists() {
+            return Ok(session);
+        }
+
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .context(format!("failed to read {}", path.display()))?;
+
+        let file: SessionFile = ::serde_json::from_str(&content)
+            .context("failed to parse session")?;
+        if file.version > VERSION {
+            bail!("session version {} is newer than the supported version {}", file.version, VERSION);
+        }
+
+        if restore {
+            debug!("restoring {} windows from the session", file.windows.len());
+            session.pending = file.windows.clone();
+            session.started = Some(Instant::now());
+        }
+        session.saved = file.windows;
+
+        Ok(session)
+    }
+
+    /// Places a new window on the workspace and at the position it had in the saved session.
+    ///
+    /// Returns false if the window isn't part of the session.
+    pub fn place(&mut self, window: Window, config: &Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces) -> bool {
+        if self.started.map(|x| x.elapsed() > TIMEOUT).unwrap_or(false) {
+            debug!("dropping {} windows of the session that never showed up", self.pending.len());
+            self.pending.clear();
+            self.started = None;
+        }
+
+        if self.pending.is_empty() {
+            return false;
+        }
+
+        let identity = Identity::new(ws, window);
+        let candidates = || {
+            self.pending.iter()
+                .enumerate()
+                .filter(|&(_, x)| x.class == identity.class && x.instance == identity.instance &&
+                    (x.role.is_none() || x.role == identity.role))
+        };
+        let found = candidates()
+            .find(|&(_, x)| matches(&x.title, &identity.title))
+            .or_else(|| candidates().next())
+            .map(|(i, _)| i);
+
+        let entry = match found {
+            Some(i) => self.pending.remove(i),
+            None => return false,
+        };
+
+        let index = match workspaces.all().iter().position(|x| x.get_tag() == entry.workspace) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        debug!("session: moving {} ({}) to workspace {}", window, entry.class, entry.workspace);
+        workspaces.add_window(Some(index), ws, config, window);
+
+        // keep the saved order among the windows restored so far
+        let workspace = workspaces.get_mut(index);
+        let position = self.placed.iter()
+            .filter(|&&(w, ref x)| x.workspace == entry.workspace && x.position < entry.position && workspace.is_managed(w))
+            .count();
+        if workspace.is_managed(window) {
+            workspace.place_window(ws, config, window, position);
+        }
+
+        self.placed.push((window, entry));
+        self.known.insert(window, identity);
+
+        true
+    }
+
+    /// Collects the current windows, `flush` writes them to the session file if they changed.
+    ///
+    /// Windows that merely closed are not written, applications quitting during logout
+    /// would otherwise empty the session right before it is needed.
+    pub fn update(&mut self, ws: &XlibWindowSystem, workspaces: &Workspaces) {
+        let mut windows = Vec::new();
+
+        for workspace in workspaces.all().iter() {
+            let managed = workspace.get_managed();
+            let unmanaged = workspace.get_unmanaged();
+            let stack = managed.visible.iter()
+                .chain(managed.hidden.iter())
+                .chain(unmanaged.visible.iter())
+                .chain(unmanaged.hidden.iter());
+
+            for (position, &window) in stack.enumerate() {
+                let identity = self.known.entry(window)
+                    .or_insert_with(|| Identity::new(ws, window))
+                    .clone();
+
+                windows.push(SessionWindow {
+                    workspace: workspace.get_tag(),
+                    position,
+                    class: identity.class,
+                    instance: identity.instance,
+                    role: identity.role,
+                    title: escape(&identity.title),
+                });
+            }
+        }
+
+        self.known.retain(|&w, _| workspaces.contains(w));
+        self.placed.retain(|&(w, _)| workspaces.contains(w));
+
+        if windows == self.saved || only_closed(&windows, &self.saved) {
+            self.unsaved = None;
+            return;
+        }
+
+        let changed = self.unsaved.take()
+            .map(|(_, x)| x)
+            .unwrap_or_else(Instant::now);
+        self.unsaved = Some((windows, changed));
+    }
+
+    /// Time until the collected changes are due to be written
+    pub fn timeout(&self) -> Option<Duration> {
+        self.unsaved.as_ref().map(|&(_, x)| WRITE_DELAY.saturating_sub(x.elapsed()))
+    }
+
+    /// Writes the collected changes once they are due, or right away with `force`
+    pub fn flush(&mut self, force: bool) {
+        match self.unsaved {
+            Some((_, changed)) if force || changed.elapsed() >= WRITE_DELAY => {}
+            _ => return,
+        }
+
+        if let Some((windows, _)) = self.unsaved.take() {
+            match self.save(&windows) {
+                Ok(_) => self.saved = windows,
+                Err(e) => error!("failed to save session: {}", utils::concat_error_chain(&e)),
+            }
+        }
+    }
+
+    fn save(&self, windows: &[SessionWindow]) -> Result<(), Error> {
+        let content = ::serde_json::to_string(&SessionFile {
+                version: VERSION,
+                windows: windows.to_vec(),
+            })
+            .context("failed to serialize session")?;
+
+        paths::write_atomic(&self.path, content.as_bytes())
+            .context("failed to save session")
+            .map_err(|e| e.into())
+    }
+}
+
+// whether `new` is `old` with some windows gone and nothing added or moved
+fn only_closed(new: &[SessionWindow], old: &[SessionWindow]) -> bool {
+    let mut old = old.iter();
+    new.len() < old.len() && new.iter().all(|x| old.any(|y| x.same_app(y)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, matches};
+
+    #[test]
+    fn title_patterns() {
+        assert!(matches("* - Mozilla Firefox", "News - Mozilla Firefox"));
+        assert!(matches("vim ?", "vim 1"));
+        assert!(matches("*", ""));
+        assert!(!matches("* - Mozilla Firefox", "Mozilla Firefox"));
+        assert!(!matches("vim ?", "vim 10"));
+    }
+
+    #[test]
+    fn escaped_titles_match_themselves_only() {
+        for title in &["a*b", "what?", "C:\\dir", "plain"] {
+            assert!(matches(&escape(title), title), "{}", title);
+        }
+        assert!(!matches(&escape("a*b"), "axxb"));
+        assert!(!matches(&escape("what?"), "whats"));
+    }
+}
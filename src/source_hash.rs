@@ -0,0 +1,45 @@
+// Hash of the xr3wm sources, also included by build.rs so the window manager knows the hash of
+// the sources it was built from and only builds the config against identical sources.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Initial value of an FNV-1a hash
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// FNV-1a, unlike the std hashers stable between compiler versions
+pub fn fnv(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |acc, &x| (acc ^ u64::from(x)).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn rust_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            rust_files(&path, files)?;
+        } else if path.extension().is_some_and(|x| x == "rs") {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Hash of the manifest, the build script and the Rust files below `src` of the sources in `dir`
+pub fn source_hash(dir: &Path) -> io::Result<String> {
+    let mut files = Vec::new();
+    rust_files(&dir.join("src"), &mut files)?;
+    files.sort();
+    files.insert(0, dir.join("build.rs"));
+    files.insert(0, dir.join("Cargo.toml"));
+
+    let mut hash = FNV_OFFSET;
+    for file in files {
+        let name = file.strip_prefix(dir).unwrap_or(&file).to_string_lossy().into_owned();
+        hash = fnv(hash, name.as_bytes());
+        hash = fnv(hash, &fs::read(&file)?);
+    }
+
+    Ok(format!("{:016x}", hash))
+}
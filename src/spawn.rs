@@ -0,0 +1,357 @@
+//! Starting programs detached from xr3wm.
+//!
+//! Programs run in their own session so signals sent to the process group of xr3wm don't reach
+//! them. Their exit is noticed through `SIGCHLD` and reaped by the event loop with `reap`, which
+//! also reaps other children nobody waits for.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use failure::*;
+use paths;
+
+/// Environment variable with the autostart entries already started in this session, so a
+/// restart in place doesn't start them again
+pub const STARTED_ENV: &str = "XR3WM_STARTED";
+
+// pids of the started programs that haven't been reaped yet
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+// pids of children waited for by their owners like the statusbar or the config build
+static OWNED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+// set until the first `reap` so children that exited before the handler was installed are reaped
+static EXITED: AtomicBool = AtomicBool::new(true);
+
+// only interrupts the poll of the event loop, reaping happens there
+extern "C" fn on_sigchld(_: libc::c_int) {
+    EXITED.store(true, Ordering::SeqCst);
+}
+
+// set once the missing children files of the kernel have been reported
+static NO_CHILDREN_FILE: AtomicBool = AtomicBool::new(false);
+
+/// Has `reap` wait for a child nobody else waits for
+pub fn track(pid: u32) {
+    if let Ok(mut children) = CHILDREN.lock() {
+        children.push(pid);
+    }
+}
+
+/// Keeps `reap` from waiting for a child its owner waits for itself
+pub fn own(pid: u32) {
+    if let Ok(mut owned) = OWNED.lock() {
+        owned.push(pid);
+    }
+}
+
+/// Hands a child back to `reap` once its owner doesn't wait for it anymore
+pub fn release(pid: u32) {
+    if let Ok(mut owned) = OWNED.lock() {
+        owned.retain(|&x| x != pid);
+    }
+}
+
+// waits for a child without blocking, returns its exit code once it exited and `None` while it
+// runs or if it isn't a child
+fn wait(pid: u32) -> Result<Option<i32>, ()> {
+    let mut status = 0;
+    match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+        0 => Ok(None),
+        x if x < 0 => Err(()),
+        _ => {
+            if libc::WIFEXITED(status) {
+                Ok(Some(libc::WEXITSTATUS(status)))
+            } else {
+                Ok(Some(-libc::WTERMSIG(status)))
+            }
+        }
+    }
+}
+
+// pids of all children of xr3wm, exited ones included until they are reaped
+fn list_children() -> Vec<u32> {
+    let tasks = match fs::read_dir("/proc/self/task") {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to list tasks: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let files: Vec<String> = tasks.filter_map(|x| x.ok())
+        .filter_map(|x| fs::read_to_string(x.path().join("children")).ok())
+        .collect();
+
+    // needs CONFIG_PROC_CHILDREN, only the children passed to `track` are reaped without it
+    if files.is_empty() && !NO_CHILDREN_FILE.swap(true, Ordering::SeqCst) {
+        warn!("the kernel doesn't list the children of processes, untracked children won't be reaped");
+    }
+
+    files.iter()
+        .flat_map(|x| x.split_whitespace().filter_map(|x| x.parse::<u32>().ok()))
+        .collect()
+}
+
+/// Installs the `SIGCHLD` handler, without `SA_RESTART` so it wakes up the event loop.
+///
+/// Programs started before xr3wm restarted in place are still its children and are reaped as well.
+#[allow(dead_code)] // only called by the event loop, not by the library
+pub fn init() {
+    let inherited = fs::read_to_string(format!("/proc/self/task/{}/children", process::id()))
+        .unwrap_or_default();
+    if let Ok(mut children) = CHILDREN.lock() {
+        children.extend(inherited.split_whitespace().filter_map(|x| x.parse::<u32>().ok()));
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = ::std::mem::zeroed();
+        action.sa_sigaction = on_sigchld as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_NOCLDSTOP;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(libc::SIGCHLD, &action, ::std::ptr::null_mut()) != 0 {
+            error!("failed to install SIGCHLD handler: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+/// Collects the exit status of started programs that exited, returns their pids and exit codes.
+///
+/// The code of programs killed by a signal is the negated signal number. Other children that
+/// exited are reaped as well unless they are owned.
+#[allow(dead_code)] // only called by the event loop, not by the library
+pub fn reap() -> Vec<(u32, i32)> {
+    if !EXITED.swap(false, Ordering::SeqCst) {
+        return Vec::new();
+    }
+
+    let mut children = match CHILDREN.lock() {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut exited = Vec::new();
+    children.retain(|&pid| {
+        match wait(pid) {
+            Ok(None) => true,
+            Ok(Some(code)) => {
+                debug!("process {} exited with {}", pid, code);
+                exited.push((pid, code));
+                false
+            }
+            Err(_) => false,
+        }
+    });
+
+    let owned = match OWNED.lock() {
+        Ok(x) => x.clone(),
+        Err(_) => return exited,
+    };
+    for pid in list_children() {
+        if children.contains(&pid) || owned.contains(&pid) {
+            continue;
+        }
+
+        if let Ok(Some(code)) = wait(pid) {
+            trace!("reaped untracked process {} with {}", pid, code);
+        }
+    }
+
+    exited
+}
+
+/// What to run
+#[derive(Clone, PartialEq)]
+pub enum Program {
+    /// Command line run by `/bin/sh -c`, so quoting, `~` and variables work like in a shell
+    Shell(String),
+    /// Program and arguments run as they are
+    Argv(Vec<String>),
+}
+
+/// A program started by `Cmd::Spawn` with its environment and working directory.
+///
+/// ```ignore
+/// Cmd::Spawn(Spawn::argv(&["urxvt", "-e", "htop"]).env("TERM", "xterm").cwd("~/src"))
+/// ```
+#[derive(Clone, PartialEq)]
+pub struct Spawn {
+    pub program: Program,
+    pub env: Vec<(String, String)>,
+    pub cwd: Option<PathBuf>,
+    /// Start in the working directory of the shell in the focused terminal instead of `cwd`
+    pub focused_cwd: bool,
+}
+
+impl Spawn {
+    pub fn shell(cmd: &str) -> Spawn {
+        Spawn {
+            program: Program::Shell(cmd.to_string()),
+            env: Vec::new(),
+            cwd: None,
+            focused_cwd: false,
+        }
+    }
+
+    #[allow(dead_code)] // only used by configs built against the library
+    pub fn argv<S: AsRef<str>>(args: &[S]) -> Spawn {
+        Spawn {
+            program: Program::Argv(args.iter().map(|x| x.as_ref().to_string()).collect()),
+            env: Vec::new(),
+            cwd: None,
+            focused_cwd: false,
+        }
+    }
+
+    /// Sets an environment variable for the program
+    pub fn env(mut self, name: &str, value: &str) -> Spawn {
+        self.env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the working directory of the program, a leading `~` is the home directory
+    pub fn cwd<P: Into<PathBuf>>(mut self, dir: P) -> Spawn {
+        self.cwd = Some(dir.into());
+        self
+    }
+
+    /// Starts the program in the working directory of the program in the focused window,
+    /// usually the shell of a terminal
+    pub fn focused_cwd(mut self) -> Spawn {
+        self.focused_cwd = true;
+        self
+    }
+
+    fn command(&self) -> Result<Command, Error> {
+        let mut cmd = match self.program {
+            Program::Shell(ref line) => {
+                let mut cmd = Command::new("/bin/sh");
+                cmd.arg("-c").arg(line);
+                cmd
+            }
+            Program::Argv(ref args) => {
+                let (program, args) = args.split_first()
+                    .ok_or_else(|| err_msg("empty argument list"))?;
+                let mut cmd = Command::new(program);
+                cmd.args(args);
+                cmd
+            }
+        };
+
+        for (name, value) in self.env.iter() {
+            cmd.env(name, value);
+        }
+
+        if let Some(ref dir) = self.cwd {
+            let dir = match dir.strip_prefix("~") {
+                Ok(x) => paths::home_dir().join(x),
+                Err(_) => dir.clone(),
+            };
+            cmd.current_dir(dir);
+        }
+
+        cmd.stdin(Stdio::null());
+        cmd.env_remove(STARTED_ENV);
+
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        Ok(cmd)
+    }
+
+    /// Starts the program without waiting for it, returns its pid
+    pub fn spawn(&self) -> Result<u32, Error> {
+        let child = self.command()?
+            .spawn()
+            .context(format!("failed to execute '{}'", self))?;
+
+        let pid = child.id();
+        debug!("started '{}' as {}", self, pid);
+
+        track(pid);
+
+        Ok(pid)
+    }
+}
+
+/// Quotes an argument for the shell unless it only consists of safe characters
+pub fn quote(arg: &str) -> String {
+    let safe = !arg.is_empty() && arg.chars().all(|c| c.is_alphanumeric() || "-_./=:,+@%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// The program as shell command line
+impl fmt::Display for Spawn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.program {
+            Program::Shell(ref line) => write!(f, "{}", line),
+            Program::Argv(ref args) => {
+                let args: Vec<String> = args.iter().map(|x| quote(x)).collect();
+                write!(f, "{}", args.join(" "))
+            }
+        }
+    }
+}
+
+/// When an autostart entry is started
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StartupPolicy {
+    /// Once per session, restarting xr3wm in place doesn't start it again
+    Once,
+    /// On startup and every reload of the config, the instance started before is stopped first
+    Reload,
+    /// Once per session and again whenever it exits
+    Restart,
+}
+
+/// Program started together with xr3wm, configured in `Config::startup`
+pub struct Startup {
+    pub spawn: Spawn,
+    pub policy: StartupPolicy,
+    /// Start after the windows are managed and the ipc socket is available instead of right away
+    pub wait_ready: bool,
+}
+
+impl Startup {
+    pub fn new(spawn: Spawn, policy: StartupPolicy) -> Startup {
+        Startup {
+            spawn,
+            policy,
+            wait_ready: false,
+        }
+    }
+
+    pub fn wait_ready(mut self) -> Startup {
+        self.wait_ready = true;
+        self
+    }
+
+    /// Identifies the entry across reloads and restarts, written like a shell command line
+    pub fn key(&self) -> String {
+        let mut key = String::new();
+        if let Some(ref dir) = self.spawn.cwd {
+            key.push_str(&format!("cd {} && ", quote(&dir.to_string_lossy())));
+        }
+        for (name, value) in self.spawn.env.iter() {
+            key.push_str(&format!("{}={} ", name, quote(value)));
+        }
+        key.push_str(&self.spawn.to_string());
+        key
+    }
+}
@@ -0,0 +1,255 @@
+//! Workspace state saved before the window manager restarts itself and restored on startup.
+//!
+//! The state is stored as JSON with a `version` field. Files written before the format was
+//! versioned, using one `screen:visible:focus:...` line per workspace, are migrated on load.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use failure::*;
+use layout::{LayoutState, Rect};
+use paths;
+use xlib::Window;
+
+/// Version of the state format written by this build
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SavedStack {
+    pub focused: Window,
+    pub visible: Vec<Window>,
+    pub hidden: Vec<Window>,
+    pub urgent: Vec<Window>,
+}
+
+/// Geometry of a floating window
+#[derive(Serialize, Deserialize)]
+pub struct SavedGeometry {
+    pub window: Window,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl SavedGeometry {
+    pub fn new(window: Window, rect: Rect) -> SavedGeometry {
+        SavedGeometry {
+            window,
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedWorkspace {
+    pub tag: String,
+    pub screen: usize,
+    pub visible: bool,
+    pub managed: SavedStack,
+    pub unmanaged: SavedStack,
+    /// State of the layout, applied if the layout in the config is still the same kind
+    #[serde(default)]
+    pub layout: Option<LayoutState>,
+    #[serde(default)]
+    pub floating: Vec<SavedGeometry>,
+    /// Windows with `_NET_WM_STATE_FULLSCREEN` set, the state is set again on restore
+    #[serde(default)]
+    pub fullscreen: Vec<Window>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedState {
+    pub version: u32,
+    pub current: usize,
+    pub workspaces: Vec<SavedWorkspace>,
+    /// Windows and the terminals they swallowed
+    #[serde(default)]
+    pub swallowed: Vec<(Window, Window)>,
+}
+
+// only the version is read first, so newer formats are recognized even if they don't parse
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+impl SavedState {
+    /// Reads the state file, failing on corrupt files and versions newer than this build knows
+    pub fn load(path: &Path) -> Result<SavedState, Error> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .context(format!("failed to read {}", path.display()))?;
+
+        SavedState::parse(&content)
+    }
+
+    fn parse(content: &str) -> Result<SavedState, Error> {
+        if !content.trim_start().starts_with('{') {
+            debug!("migrating workspace state from the unversioned format");
+            return SavedState::parse_legacy(content);
+        }
+
+        let version = ::serde_json::from_str::<Version>(content)
+            .context("workspace state has no version")?
+            .version;
+        if version > VERSION {
+            bail!("workspace state version {} is newer than the supported version {}", version, VERSION);
+        }
+
+        ::serde_json::from_str(content)
+            .context("failed to parse workspace state")
+            .map_err(|e| e.into())
+    }
+
+    /// Writes the state to a temporary file first so a crash never leaves a half written state
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let content = ::serde_json::to_string(self)
+            .context("failed to serialize workspace state")?;
+
+        paths::write_atomic(path, content.as_bytes())
+            .context("failed to save workspace state")
+            .map_err(|e| e.into())
+    }
+
+    // first line is the current workspace, then `screen:visible:managed focus:unmanaged focus:
+    // managed visible:managed hidden:unmanaged visible:unmanaged hidden` for each workspace
+    fn parse_legacy(content: &str) -> Result<SavedState, Error> {
+        let mut lines = content.lines();
+        let current = lines.next()
+            .ok_or_else(|| err_msg("workspace state is empty"))?
+            .trim()
+            .parse::<usize>()
+            .context("failed to parse the current workspace")?;
+
+        let windows = |x: &str| -> Vec<Window> {
+            x.split(',')
+                .filter_map(|w| w.parse::<Window>().ok())
+                .collect()
+        };
+
+        let workspaces = lines.enumerate()
+            .map(|(i, line)| {
+                let data: Vec<&str> = line.split(':').collect();
+                if data.len() != 8 {
+                    bail!("workspace {} has {} fields instead of 8", i + 1, data.len());
+                }
+
+                Ok(SavedWorkspace {
+                    // tags were not saved, workspaces are matched by position
+                    tag: String::new(),
+                    screen: data[0].parse::<usize>()
+                        .context("failed to parse screen number value")?,
+                    visible: data[1].parse::<bool>()
+                        .context("failed to parse visible boolean value")?,
+                    managed: SavedStack {
+                        focused: windows(data[2]).first().cloned().unwrap_or(0),
+                        visible: windows(data[4]),
+                        hidden: windows(data[5]),
+                        urgent: Vec::new(),
+                    },
+                    unmanaged: SavedStack {
+                        focused: windows(data[3]).first().cloned().unwrap_or(0),
+                        visible: windows(data[6]),
+                        hidden: windows(data[7]),
+                        urgent: Vec::new(),
+                    },
+                    layout: None,
+                    floating: Vec::new(),
+                    fullscreen: Vec::new(),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(SavedState {
+            version: 0,
+            current,
+            workspaces,
+            swallowed: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_format() {
+        let state = SavedState::parse("1\n0:true:5:0:5,6:7::\n1:false::9:::8:9\n").unwrap();
+
+        assert_eq!(state.version, 0);
+        assert_eq!(state.current, 1);
+        assert_eq!(state.workspaces.len(), 2);
+
+        let first = &state.workspaces[0];
+        assert_eq!((first.screen, first.visible), (0, true));
+        assert_eq!(first.managed.focused, 5);
+        assert_eq!(first.managed.visible, vec![5, 6]);
+        assert_eq!(first.managed.hidden, vec![7]);
+        assert!(first.unmanaged.visible.is_empty());
+
+        let second = &state.workspaces[1];
+        assert_eq!((second.screen, second.visible), (1, false));
+        assert_eq!(second.unmanaged.focused, 9);
+        assert_eq!(second.unmanaged.visible, vec![8]);
+        assert_eq!(second.unmanaged.hidden, vec![9]);
+    }
+
+    #[test]
+    fn legacy_wrong_field_count() {
+        assert!(SavedState::parse("0\n0:true:5:0:5:::\n").is_ok());
+        assert!(SavedState::parse("0\n0:true:5:0:5::\n").is_err());
+        assert!(SavedState::parse("0\n0:true:5:0:5::::\n").is_err());
+    }
+
+    #[test]
+    fn garbage() {
+        for content in &["", "garbage", "0\nx:true:::::::", "{", "{\"current\": 0}", "{\"version\": 1}", "[1, 2]"] {
+            assert!(SavedState::parse(content).is_err(), "'{}' should not parse", content);
+        }
+    }
+
+    #[test]
+    fn newer_version() {
+        let content = format!("{{\"version\": {}, \"unknown\": true}}", VERSION + 1);
+        let err = SavedState::parse(&content).err().expect("newer version accepted");
+
+        assert!(err.to_string().contains("newer"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let state = SavedState {
+            version: VERSION,
+            current: 1,
+            workspaces: vec![SavedWorkspace {
+                tag: "web".to_string(),
+                screen: 0,
+                visible: true,
+                managed: SavedStack {
+                    focused: 3,
+                    visible: vec![3, 4],
+                    hidden: vec![5],
+                    urgent: vec![4],
+                },
+                unmanaged: SavedStack::default(),
+                layout: None,
+                floating: vec![SavedGeometry::new(6, Rect { x: 1, y: 2, width: 3, height: 4 })],
+                fullscreen: vec![3],
+            }],
+            swallowed: vec![(7, 8)],
+        };
+
+        let content = ::serde_json::to_string(&state).unwrap();
+        let parsed = SavedState::parse(&content).unwrap();
+
+        assert_eq!(::serde_json::to_string(&parsed).unwrap(), content);
+        assert_eq!(parsed.workspaces[0].floating[0].height, 4);
+        assert_eq!(parsed.swallowed, vec![(7, 8)]);
+    }
+}
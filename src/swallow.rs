@@ -0,0 +1,41 @@
+//! Finding the terminal a new window was started from, so the window can take its place.
+//!
+//! The terminal is recognized by its class, see `Config::swallow`, and by the process of the
+//! window being a descendant of the terminal process according to `_NET_WM_PID` and `/proc`.
+
+use config::Config;
+use procfs::Procfs;
+use workspaces::Workspaces;
+use xlib::Window;
+use xlib_window_system::XlibWindowSystem;
+
+/// Terminal to swallow for a newly mapped window, dialogs and floating windows don't swallow
+pub fn find_terminal(window: Window, config: &Config, ws: &XlibWindowSystem, workspaces: &Workspaces) -> Option<Window> {
+    let swallow = &config.swallow;
+    if swallow.terminals.is_empty() || ws.is_window_floating(window) {
+        return None;
+    }
+
+    let class = ws.get_class_name(window);
+    if swallow.exclude.contains(&class) || swallow.terminals.contains(&class) {
+        return None;
+    }
+
+    let pid = ws.get_window_pid(window)?;
+    let procfs = Procfs::default();
+
+    // the focused terminal most likely started the program, it is checked first
+    let focused = workspaces.current().focused_window();
+    let mut terminals: Vec<Window> = workspaces.all().iter()
+        .flat_map(|x| x.get_managed().visible.clone())
+        .filter(|&x| swallow.terminals.contains(&ws.get_class_name(x)))
+        .collect();
+    terminals.sort_by_key(|&x| x != focused);
+
+    terminals.into_iter().find(|&terminal| {
+        match ws.get_window_pid(terminal) {
+            Some(x) => x != pid && procfs.is_descendant(pid, x),
+            None => false,
+        }
+    })
+}
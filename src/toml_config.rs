@@ -0,0 +1,581 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use failure::*;
+use commands::{Cmd, CmdManage, ManageHook};
+use config::{Config, Keybinding, Statusbar, Swallow};
+use keycode::*;
+use layout::*;
+use spawn::{Spawn, Startup, StartupPolicy};
+use workspaces::WorkspaceConfig;
+
+/// Layout description, nested layouts are built from the inside out
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum LayoutSpec {
+    Tall {
+        #[serde(default = "default_num_masters")]
+        num_masters: usize,
+        #[serde(default = "default_ratio")]
+        ratio: f32,
+        #[serde(default = "default_ratio_increment")]
+        ratio_increment: f32,
+    },
+    Full,
+    Strut {
+        layout: Box<LayoutSpec>,
+    },
+    Gap {
+        screen_gap: u32,
+        window_gap: u32,
+        layout: Box<LayoutSpec>,
+    },
+    Mirror {
+        layout: Box<LayoutSpec>,
+    },
+    Choose {
+        layouts: Vec<LayoutSpec>,
+    },
+}
+
+fn default_num_masters() -> usize { 1 }
+fn default_ratio() -> f32 { 0.5 }
+fn default_ratio_increment() -> f32 { 0.05 }
+
+impl LayoutSpec {
+    pub fn build(&self) -> Result<Box<dyn Layout>, Error> {
+        Ok(match *self {
+            LayoutSpec::Tall { num_masters, ratio, ratio_increment } => {
+                TallLayout::new(num_masters, ratio, ratio_increment)
+            }
+            LayoutSpec::Full => FullLayout::new(),
+            LayoutSpec::Strut { ref layout } => StrutLayout::new(layout.build()?),
+            LayoutSpec::Gap { screen_gap, window_gap, ref layout } => {
+                GapLayout::new(screen_gap, window_gap, layout.build()?)
+            }
+            LayoutSpec::Mirror { ref layout } => MirrorLayout::new(layout.build()?),
+            LayoutSpec::Choose { ref layouts } => {
+                ChooseLayout::new(layouts.iter()
+                    .map(|x| x.build())
+                    .collect::<Result<Vec<_>, Error>>()?)
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkspaceSpec {
+    tag: String,
+    #[serde(default)]
+    screen: usize,
+    layout: Option<LayoutSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeybindingSpec {
+    keys: String,
+    command: Option<String>,
+    #[serde(default)]
+    remove: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManageHookSpec {
+    class: String,
+    action: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum PolicySpec {
+    #[default]
+    Once,
+    Reload,
+    Restart,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StartupSpec {
+    command: String,
+    #[serde(default)]
+    policy: PolicySpec,
+    #[serde(default)]
+    wait_ready: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StatusbarSpec {
+    executable: String,
+    args: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SwallowSpec {
+    #[serde(default)]
+    terminals: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ColorSpec {
+    border: Option<String>,
+    border_focus: Option<String>,
+    border_urgent: Option<String>,
+}
+
+/// Declarative alternative to the compiled `config.rs`.
+///
+/// Every setting is optional and falls back to `Config::default()`. Keybindings are added to the
+/// default ones, replacing defaults with the same key combination. `remove = true` instead of a
+/// command only removes the default. Default bindings of workspaces beyond the configured ones
+/// are dropped.
+///
+/// ```toml
+/// mod_key = "mod4"
+/// border_width = 2
+/// watch_config = true
+/// session_restore = true
+/// layout = { type = "strut", layout = { type = "tall", ratio = 0.6 } }
+///
+/// [colors]
+/// border_focus = "#2a82e6"
+///
+/// [[workspaces]]
+/// tag = "web"
+/// layout = { type = "choose", layouts = [{ type = "tall" }, { type = "full" }] }
+///
+/// [[workspaces]]
+/// tag = "chat"
+///
+/// [[keybindings]]
+/// keys = "Shift+Return"
+/// command = "exec urxvt"
+///
+/// [[keybindings]]
+/// keys = "Shift+c"
+/// remove = true
+///
+/// [[manage_hooks]]
+/// class = "Slack"
+/// action = "move 2"
+///
+/// [[startup]]
+/// command = "picom"
+/// policy = "restart"
+///
+/// [[startup]]
+/// command = "feh --bg-fill ~/wallpaper.png"
+/// policy = "reload"
+/// wait_ready = true
+///
+/// [swallow]
+/// terminals = ["URxvt", "Alacritty"]
+/// exclude = ["Gimp"]
+///
+/// [statusbar]
+/// executable = "xmobar"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TomlConfig {
+    mod_key: Option<String>,
+    border_width: Option<u32>,
+    greedy_view: Option<bool>,
+    watch_config: Option<bool>,
+    session_restore: Option<bool>,
+    layout: Option<LayoutSpec>,
+    #[serde(default)]
+    colors: ColorSpec,
+    workspaces: Option<Vec<WorkspaceSpec>>,
+    #[serde(default)]
+    keybindings: Vec<KeybindingSpec>,
+    #[serde(default)]
+    manage_hooks: Vec<ManageHookSpec>,
+    #[serde(default)]
+    startup: Vec<StartupSpec>,
+    swallow: Option<SwallowSpec>,
+    statusbar: Option<StatusbarSpec>,
+}
+
+fn parse_modifier(name: &str) -> Result<u8, Error> {
+    Ok(match name.to_lowercase().as_str() {
+        "shift" => MOD_SHIFT,
+        "lock" => MOD_LOCK,
+        "control" | "ctrl" => MOD_CONTROL,
+        "mod1" | "alt" => MOD_1,
+        "mod2" => MOD_2,
+        "mod3" => MOD_3,
+        "mod4" | "super" => MOD_4,
+        "mod5" => MOD_5,
+        x => bail!("unknown modifier '{}'", x),
+    })
+}
+
+// parses key combinations like "Shift+Return", the mod key is implied
+fn parse_keys(keys: &str) -> Result<(u8, String), Error> {
+    let mut parts: Vec<&str> = keys.split('+').map(|x| x.trim()).collect();
+    let key = parts.pop()
+        .filter(|x| !x.is_empty())
+        .ok_or_else(|| format_err!("missing key in '{}'", keys))?;
+
+    let mods = parts.iter()
+        .map(|x| parse_modifier(x))
+        .collect::<Result<Vec<u8>, Error>>()?
+        .iter()
+        .fold(0, |acc, x| acc | x);
+
+    Ok((mods, key.to_string()))
+}
+
+fn parse_color(color: &str) -> Result<u32, Error> {
+    let hex = color.trim_start_matches('#').trim_start_matches("0x");
+
+    u32::from_str_radix(hex, 16)
+        .map_err(|_| format_err!("invalid color '{}', expected '#rrggbb'", color))
+}
+
+impl TomlConfig {
+    pub fn load(path: &Path) -> Result<TomlConfig, Error> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .context(format!("failed to read {}", path.display()))?;
+
+        ::toml::from_str(&content)
+            .context(format!("failed to parse {}", path.display()))
+            .map_err(|e| e.into())
+    }
+
+    pub fn apply(self, cfg: &mut Config) -> Result<(), Error> {
+        if let Some(ref mod_key) = self.mod_key {
+            cfg.mod_key = parse_modifier(mod_key)
+                .context("invalid mod_key")?;
+        }
+
+        if let Some(border_width) = self.border_width {
+            cfg.border_width = border_width;
+        }
+
+        if let Some(greedy_view) = self.greedy_view {
+            cfg.greedy_view = greedy_view;
+        }
+
+        if let Some(watch_config) = self.watch_config {
+            cfg.watch_config = watch_config;
+        }
+
+        if let Some(session_restore) = self.session_restore {
+            cfg.session_restore = session_restore;
+        }
+
+        if let Some(ref color) = self.colors.border {
+            cfg.border_color = parse_color(color)?;
+        }
+        if let Some(ref color) = self.colors.border_focus {
+            cfg.border_focus_color = parse_color(color)?;
+        }
+        if let Some(ref color) = self.colors.border_urgent {
+            cfg.border_urgent_color = parse_color(color)?;
+        }
+
+        if let Some(ref layout) = self.layout {
+            let layout = layout.build()
+                .context("invalid default layout")?;
+            for ws in cfg.workspaces.iter_mut() {
+                ws.layout = layout.copy();
+            }
+        }
+
+        if let Some(workspaces) = self.workspaces {
+            let default_layout = self.layout.clone();
+
+            cfg.workspaces = workspaces.into_iter()
+                .map(|x| {
+                    let layout = match x.layout.or_else(|| default_layout.clone()) {
+                        Some(spec) => spec.build()
+                            .context(format!("invalid layout for workspace '{}'", x.tag))?,
+                        None => StrutLayout::new(TallLayout::new(1, 0.5, 0.05)),
+                    };
+
+                    Ok(WorkspaceConfig {
+                        tag: x.tag,
+                        screen: x.screen,
+                        layout,
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+
+            // default bindings of workspaces that don't exist anymore would fail the validation
+            let num_ws = cfg.workspaces.len();
+            cfg.keybindings.retain(|x| match x.cmd {
+                Cmd::SwitchWorkspace(i) | Cmd::MoveToWorkspace(i) => i <= num_ws,
+                _ => true,
+            });
+        }
+
+        for binding in self.keybindings.iter() {
+            let (mods, key) = parse_keys(&binding.keys)
+                .context(format!("invalid keybinding '{}'", binding.keys))?;
+            cfg.keybindings.retain(|x| x.mods != mods || x.key != key);
+
+            let command = match (binding.command.as_ref(), binding.remove) {
+                (Some(command), false) => command,
+                (None, true) => continue,
+                (Some(_), true) => bail!("keybinding '{}' has a command but is removed", binding.keys),
+                (None, false) => bail!("keybinding '{}' has no command", binding.keys),
+            };
+            let cmd = command.parse::<Cmd>()
+                .context(format!("invalid command for keybinding '{}'", binding.keys))?;
+
+            cfg.keybindings.push(Keybinding {
+                mods,
+                key,
+                cmd,
+            });
+        }
+
+        for hook in self.manage_hooks.iter() {
+            cfg.manage_hooks.push(ManageHook {
+                class_name: hook.class.clone(),
+                cmd: hook.action.parse::<CmdManage>()
+                    .context(format!("invalid manage hook for '{}'", hook.class))?,
+            });
+        }
+
+        for entry in self.startup.iter() {
+            if entry.command.trim().is_empty() {
+                bail!("startup entry without command");
+            }
+
+            let policy = match entry.policy {
+                PolicySpec::Once => StartupPolicy::Once,
+                PolicySpec::Reload => StartupPolicy::Reload,
+                PolicySpec::Restart => StartupPolicy::Restart,
+            };
+            let mut startup = Startup::new(Spawn::shell(&entry.command), policy);
+            if entry.wait_ready {
+                startup = startup.wait_ready();
+            }
+            cfg.startup.push(startup);
+        }
+
+        if let Some(swallow) = self.swallow {
+            cfg.swallow = Swallow {
+                terminals: swallow.terminals,
+                exclude: swallow.exclude,
+            };
+        }
+
+        if let Some(statusbar) = self.statusbar {
+            cfg.statusbar = Some(if statusbar.executable == "xmobar" && statusbar.args.is_none() {
+                Statusbar::xmobar()
+            } else {
+                Statusbar::new(statusbar.executable, statusbar.args, Box::new(Statusbar::xmobar_format))
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(content: &str) -> Result<Config, Error> {
+        let mut config = Config::default();
+        ::toml::from_str::<TomlConfig>(content)?
+            .apply(&mut config)?;
+        Ok(config)
+    }
+
+    fn binding<'a>(config: &'a Config, mods: u8, key: &str) -> Option<&'a Cmd> {
+        config.keybindings.iter()
+            .find(|x| x.mods == mods && x.key == key)
+            .map(|x| &x.cmd)
+    }
+
+    // the example of the `TomlConfig` documentation
+    #[test]
+    fn example() {
+        let config = apply(r##"
+            mod_key = "mod4"
+            border_width = 2
+            watch_config = true
+            session_restore = true
+            layout = { type = "strut", layout = { type = "tall", ratio = 0.6 } }
+
+            [colors]
+            border_focus = "#2a82e6"
+
+            [[workspaces]]
+            tag = "web"
+            layout = { type = "choose", layouts = [{ type = "tall" }, { type = "full" }] }
+
+            [[workspaces]]
+            tag = "chat"
+
+            [[keybindings]]
+            keys = "Shift+Return"
+            command = "exec urxvt"
+
+            [[keybindings]]
+            keys = "Shift+c"
+            remove = true
+
+            [[manage_hooks]]
+            class = "Slack"
+            action = "move 2"
+
+            [[startup]]
+            command = "picom"
+            policy = "restart"
+
+            [[startup]]
+            command = "feh --bg-fill ~/wallpaper.png"
+            policy = "reload"
+            wait_ready = true
+
+            [swallow]
+            terminals = ["URxvt", "Alacritty"]
+            exclude = ["Gimp"]
+
+            [statusbar]
+            executable = "xmobar"
+        "##).unwrap();
+
+        assert_eq!(config.mod_key, MOD_4);
+        assert!(config.watch_config && config.session_restore);
+        assert_eq!(config.workspaces.len(), 2);
+        assert!(binding(&config, 0, "3").is_none());
+        assert_eq!(config.startup.len(), 2);
+        assert!(config.startup[1].policy == StartupPolicy::Reload && config.startup[1].wait_ready);
+        assert_eq!(config.swallow.terminals, ["URxvt", "Alacritty"]);
+        assert!(config.statusbar.is_some());
+        assert!(config.validate(None).iter().all(|x| x.severity != ::config::Severity::Error));
+    }
+
+    #[test]
+    fn layouts() {
+        let config = apply(r#"
+            layout = { type = "mirror", layout = { type = "tall" } }
+
+            [[workspaces]]
+            tag = "a"
+
+            [[workspaces]]
+            tag = "b"
+            screen = 1
+            layout = { type = "gap", screen_gap = 4, window_gap = 2, layout = { type = "full" } }
+
+            [[workspaces]]
+            tag = "c"
+            layout = { type = "choose", layouts = [{ type = "strut", layout = { type = "tall" } }, { type = "full" }] }
+        "#).unwrap();
+
+        let names: Vec<String> = config.workspaces.iter().map(|x| x.layout.name()).collect();
+        assert_eq!(names, ["Mirror(Tall)", "Full", "Tall"]);
+        assert_eq!(config.workspaces[1].screen, 1);
+
+        let mut choose = config.workspaces[2].layout.copy();
+        choose.send_msg(LayoutMsg::NextLayout);
+        assert_eq!(choose.name(), "Full");
+
+        // the default layout also applies to the default workspaces
+        let config = apply(r#"layout = { type = "full" }"#).unwrap();
+        assert!(config.workspaces.iter().all(|x| x.layout.name() == "Full"));
+
+        assert!(apply(r#"layout = { type = "spiral" }"#).is_err());
+        assert!(apply(r#"layout = { type = "tall", ratio = "half" }"#).is_err());
+        assert!(apply(r#"layout = { type = "mirror" }"#).is_err());
+    }
+
+    #[test]
+    fn keybindings() {
+        let config = apply(r#"
+            [[keybindings]]
+            keys = "Return"
+            command = "exec urxvt"
+
+            [[keybindings]]
+            keys = "ctrl + Shift + t"
+            command = "switch-workspace 3"
+
+            [[keybindings]]
+            keys = "d"
+            remove = true
+        "#).unwrap();
+        let default = Config::default();
+
+        assert!(binding(&config, 0, "Return") == Some(&Cmd::Exec("urxvt".to_string())));
+        assert!(binding(&config, MOD_CONTROL | MOD_SHIFT, "t") == Some(&Cmd::SwitchWorkspace(3)));
+        assert!(binding(&config, 0, "d").is_none());
+        assert_eq!(config.keybindings.len(), default.keybindings.len());
+        assert_eq!(config.keybindings.iter().filter(|x| x.key == "Return").count(), 2);
+
+        for content in &[r#"keybindings = [{ keys = "x" }]"#,
+                         r#"keybindings = [{ keys = "x", command = "exit", remove = true }]"#,
+                         r#"keybindings = [{ keys = "Hyper+x", command = "exit" }]"#,
+                         r#"keybindings = [{ keys = "Shift+", command = "exit" }]"#,
+                         r#"keybindings = [{ keys = "x", command = "fly" }]"#] {
+            assert!(apply(content).is_err(), "'{}' should not apply", content);
+        }
+    }
+
+    #[test]
+    fn manage_hooks() {
+        let config = apply(r#"
+            [[manage_hooks]]
+            class = "Firefox"
+            action = "move 2"
+
+            [[manage_hooks]]
+            class = "Slack"
+            action = "move 8"
+        "#).unwrap();
+
+        assert_eq!(config.manage_hooks.len(), 2);
+        assert_eq!(config.manage_hooks[1].class_name, "Slack");
+        assert!(matches!(config.manage_hooks[0].cmd, CmdManage::Move(2)));
+        assert!(matches!(config.manage_hooks[1].cmd, CmdManage::Move(8)));
+    }
+
+    #[test]
+    fn colors() {
+        let config = apply(r##"
+            [colors]
+            border = "#112233"
+            border_focus = "0xaabbcc"
+            border_urgent = "ff0000"
+        "##).unwrap();
+
+        assert_eq!(config.border_color, 0x0011_2233);
+        assert_eq!(config.border_focus_color, 0x00aa_bbcc);
+        assert_eq!(config.border_urgent_color, 0x00ff_0000);
+        assert_eq!(apply("").unwrap().border_color, Config::default().border_color);
+
+        assert!(apply(r##"colors = { border = "#red" }"##).is_err());
+        assert!(apply(r##"colors = { background = "#000000" }"##).is_err());
+    }
+
+    #[test]
+    fn unsupported_actions() {
+        for action in &["float", "fullscreen", "ignore"] {
+            let content = format!("manage_hooks = [{{ class = \"mpv\", action = \"{}\" }}]", action);
+            let err = apply(&content).err().expect("unsupported action accepted");
+            let msg = err.iter_chain().map(|x| x.to_string()).collect::<Vec<_>>().join(": ");
+
+            assert!(msg.contains("is not supported yet"), "unexpected error: {}", msg);
+        }
+
+        assert!(apply(r#"manage_hooks = [{ class = "mpv", action = "move 0x" }]"#).is_err());
+    }
+}
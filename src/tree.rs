@@ -0,0 +1,174 @@
+use layout::Rect;
+use workspaces::{Workspaces, Workspace, Stack};
+use xlib::Window;
+use xlib_window_system::{XlibWindowSystem, Strut};
+
+#[derive(Serialize)]
+pub struct RectNode {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<Rect> for RectNode {
+    fn from(rect: Rect) -> RectNode {
+        RectNode {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StrutNode {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
+impl From<Strut> for StrutNode {
+    fn from(strut: Strut) -> StrutNode {
+        StrutNode {
+            left: strut.0,
+            right: strut.1,
+            top: strut.2,
+            bottom: strut.3,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ScreenNode {
+    pub id: usize,
+    pub rect: RectNode,
+    pub strut: StrutNode,
+}
+
+#[derive(Serialize)]
+pub struct WindowNode {
+    pub id: Window,
+    pub class: String,
+    pub title: String,
+    pub geometry: RectNode,
+}
+
+impl WindowNode {
+    pub fn new(ws: &XlibWindowSystem, window: Window) -> WindowNode {
+        WindowNode {
+            id: window,
+            class: ws.get_class_name(window),
+            title: ws.get_window_title(window),
+            geometry: ws.get_geometry(window).into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StackNode {
+    pub focused_window: Window,
+    pub visible: Vec<WindowNode>,
+    pub hidden: Vec<WindowNode>,
+    pub urgent: Vec<Window>,
+}
+
+impl StackNode {
+    fn new(ws: &XlibWindowSystem, stack: &Stack) -> StackNode {
+        StackNode {
+            focused_window: stack.focused_window,
+            visible: stack.visible.iter().map(|&w| WindowNode::new(ws, w)).collect(),
+            hidden: stack.hidden.iter().map(|&w| WindowNode::new(ws, w)).collect(),
+            urgent: stack.urgent.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceNode {
+    pub tag: String,
+    pub screen: usize,
+    pub current: bool,
+    pub visible: bool,
+    pub layout: String,
+    pub focused_window: Window,
+    pub urgent: Vec<Window>,
+    pub managed: StackNode,
+    pub unmanaged: StackNode,
+}
+
+impl WorkspaceNode {
+    fn new(ws: &XlibWindowSystem, workspace: &Workspace, current: bool) -> WorkspaceNode {
+        WorkspaceNode {
+            tag: workspace.get_tag(),
+            screen: workspace.get_screen(),
+            current,
+            visible: workspace.is_visible(),
+            layout: workspace.get_layout().name(),
+            focused_window: workspace.focused_window(),
+            urgent: workspace.all_urgent(),
+            managed: StackNode::new(ws, workspace.get_managed()),
+            unmanaged: StackNode::new(ws, workspace.get_unmanaged()),
+        }
+    }
+}
+
+/// Complete window manager state as returned by the `get-tree` ipc query
+#[derive(Serialize)]
+pub struct Tree {
+    pub current: String,
+    pub screens: Vec<ScreenNode>,
+    pub workspaces: Vec<WorkspaceNode>,
+}
+
+impl Tree {
+    pub fn new(ws: &XlibWindowSystem, workspaces: &Workspaces) -> Tree {
+        Tree {
+            current: workspaces.current().get_tag(),
+            screens: ws.get_screen_infos()
+                .into_iter()
+                .enumerate()
+                .map(|(id, rect)| {
+                    ScreenNode {
+                        id,
+                        rect: rect.into(),
+                        strut: ws.get_strut(rect).into(),
+                    }
+                })
+                .collect(),
+            workspaces: workspaces.all()
+                .iter()
+                .enumerate()
+                .map(|(i, x)| WorkspaceNode::new(ws, x, i == workspaces.get_index()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceWindowNode {
+    pub workspace: String,
+    #[serde(flatten)]
+    pub window: WindowNode,
+}
+
+/// Flat list of all managed windows as returned by the `get-windows` ipc query
+pub fn windows(ws: &XlibWindowSystem, workspaces: &Workspaces) -> Vec<WorkspaceWindowNode> {
+    workspaces.all()
+        .iter()
+        .flat_map(|x| {
+            let tag = x.get_tag();
+            x.all()
+                .into_iter()
+                .map(move |w| (tag.clone(), w))
+        })
+        .map(|(tag, w)| {
+            WorkspaceWindowNode {
+                workspace: tag,
+                window: WindowNode::new(ws, w),
+            }
+        })
+        .collect()
+}
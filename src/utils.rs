@@ -1,12 +1,16 @@
-use std::process::{Command, Child};
+use std::process::Command;
 use failure::*;
+use spawn;
 
-pub(crate) fn xmessage(msg: &str) -> Result<Child, Error> {
-    Command::new("xmessage")
+// shows a message without waiting for it to be closed, the event loop reaps xmessage afterwards
+pub(crate) fn xmessage(msg: &str) -> Result<(), Error> {
+    let child = Command::new("xmessage")
         .arg("-center")
         .arg(msg)
-        .spawn()
-        .map_err(|e| e.into())
+        .spawn()?;
+
+    spawn::track(child.id());
+    Ok(())
 }
 
 pub(crate) fn concat_error_chain(err: &Error) -> String {
@@ -0,0 +1,115 @@
+//! Watching the config file with inotify to reload automatically after it was saved.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use failure::*;
+use paths;
+
+/// Time without further changes before a reload starts, editors often write several times per save
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+pub struct ConfigWatcher {
+    fd: RawFd,
+    file: PathBuf,
+    changed: Option<Instant>,
+}
+
+impl ConfigWatcher {
+    /// Watches the directory of the config file in use.
+    ///
+    /// Only finished writes and files renamed into place count as change, a file that is still
+    /// being written never triggers a reload.
+    pub fn new() -> Result<ConfigWatcher, Error> {
+        let file = paths::config_toml()
+            .unwrap_or_else(paths::config_rs);
+        let dir = file.parent()
+            .map(|x| x.to_path_buf())
+            .ok_or_else(|| format_err!("{} has no parent directory", file.display()))?;
+        let dir_c = CString::new(dir.as_os_str().as_bytes())
+            .context("invalid config directory")?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().context("failed to initialize inotify").into());
+        }
+
+        let wd = unsafe { libc::inotify_add_watch(fd, dir_c.as_ptr(), libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) };
+        if wd < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err.context(format!("failed to watch {}", dir.display())).into());
+        }
+
+        debug!("watching {} for changes", file.display());
+
+        Ok(ConfigWatcher {
+            fd,
+            file,
+            changed: None,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Reads the pending inotify events without blocking
+    pub fn read_events(&mut self) {
+        let name = self.file.file_name();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len <= 0 {
+                let err = io::Error::last_os_error();
+                if len < 0 && err.kind() != io::ErrorKind::WouldBlock && err.kind() != io::ErrorKind::Interrupted {
+                    error!("failed to read inotify events: {}", err);
+                }
+                break;
+            }
+
+            let mut offset = 0;
+            while offset < len as usize {
+                let event = unsafe { &*(buf.as_ptr().add(offset) as *const libc::inotify_event) };
+                let header = mem::size_of::<libc::inotify_event>();
+
+                if event.len > 0 {
+                    let file = unsafe { CStr::from_ptr(buf.as_ptr().add(offset + header) as *const libc::c_char) };
+                    if name == Some(OsStr::from_bytes(file.to_bytes())) {
+                        trace!("config changed");
+                        self.changed = Some(Instant::now());
+                    }
+                }
+
+                offset += header + event.len as usize;
+            }
+        }
+    }
+
+    /// Time left until a change should be acted on
+    pub fn timeout(&self) -> Option<Duration> {
+        self.changed.map(|x| DEBOUNCE.checked_sub(x.elapsed()).unwrap_or_default())
+    }
+
+    /// Whether the config changed and has been left alone long enough, resets the change
+    pub fn take_change(&mut self) -> bool {
+        match self.changed {
+            Some(x) if x.elapsed() >= DEBOUNCE => {
+                self.changed = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
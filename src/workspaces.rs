@@ -6,13 +6,11 @@ use layout::LayoutMsg;
 use xlib::Window;
 use xlib_window_system::XlibWindowSystem;
 use self::MoveOp::*;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::fs::{File, remove_file};
-use std::path::Path;
+use std::fs::remove_file;
 use std::default::Default;
 use std::cmp;
-use failure::*;
+use paths;
+use state::{self, SavedGeometry, SavedStack, SavedState, SavedWorkspace};
 
 #[derive(Default)]
 pub struct Stack {
@@ -23,6 +21,29 @@ pub struct Stack {
 }
 
 impl Stack {
+    // keeps only the windows that still exist
+    fn restore(saved: &SavedStack, windows: &[Window]) -> Stack {
+        let existing = |x: &Vec<Window>| -> Vec<Window> {
+            x.iter().filter(|w| windows.contains(w)).cloned().collect()
+        };
+
+        Stack {
+            focused_window: if windows.contains(&saved.focused) { saved.focused } else { 0 },
+            hidden: existing(&saved.hidden),
+            visible: existing(&saved.visible),
+            urgent: existing(&saved.urgent),
+        }
+    }
+
+    fn save(&self) -> SavedStack {
+        SavedStack {
+            focused: self.focused_window,
+            visible: self.visible.clone(),
+            hidden: self.hidden.clone(),
+            urgent: self.urgent.clone(),
+        }
+    }
+
     fn all(&self) -> Vec<Window> {
         self.hidden.iter().chain(self.visible.iter()).copied().collect()
     }
@@ -32,7 +53,7 @@ impl Stack {
     }
 
     fn contains(&self, window: Window) -> bool {
-        self.all().iter().any(|&x| x == window)
+        self.all().contains(&window)
     }
 
     fn is_urgent(&self) -> bool {
@@ -56,14 +77,6 @@ impl Stack {
         self.visible.remove(index);
         self.hidden.push(window);
     }
-
-    fn remove(&mut self, index: usize) {
-        if index < self.hidden.len() {
-            self.hidden.remove(index);
-        } else {
-            self.visible.remove(index - self.hidden.len());
-        }
-    }
 }
 
 pub struct WorkspaceInfo {
@@ -129,69 +142,7 @@ impl Workspace {
         }
     }
 
-    pub fn new(tag: &str, layout: Box<dyn Layout>, windows: &[Window], data: &str) -> Result<Workspace, Error> {
-        let data: Vec<&str> = data.split(':').collect();
-
-        if data.len() != 8 {
-            bail!("Invalid workspace data fragment count: {}", data.len());
-        }
-
-        let screen = data[0].parse::<usize>()
-                .context("failed to parse screen number value")?;
-        let visible = data[1].parse::<bool>()
-                .context("failed to parse visible boolean value")?;
-
-        let data: Vec<Vec<u64>> = data.iter()
-            .skip(2)
-            .map(|x| {
-                x.split(',')
-                    .filter_map(|w| w.parse::<u64>().ok())
-                    .filter(|w| windows.contains(w))
-                    .collect()
-            })
-            .collect();
-
-        let managed = Stack {
-            focused_window: *data[0].get(0).unwrap_or(&0),
-            visible: data[2].clone(),
-            hidden: data[3].clone(),
-            ..Default::default()
-        };
-
-        let unmanaged = Stack {
-            focused_window: *data[1].get(0).unwrap_or(&0),
-            visible: data[4].clone(),
-            hidden: data[5].clone(),
-            ..Default::default()
-        };
-
-        Ok(Workspace {
-            managed,
-            unmanaged,
-            tag: tag.to_string(),
-            screen,
-            visible,
-            layout,
-        })
-    }
-
-    pub fn serialize(&self) -> String {
-        let windows = vec![
-            self.managed.visible.iter().map(|&x| x.to_string()).collect::<Vec<String>>().join(","),
-            self.managed.hidden.iter().map(|&x| x.to_string()).collect::<Vec<String>>().join(","),
-            self.unmanaged.visible.iter().map(|&x| x.to_string()).collect::<Vec<String>>().join(","),
-            self.unmanaged.hidden.iter().map(|&x| x.to_string()).collect::<Vec<String>>().join(","),
-        ];
-
-        format!("{}:{}:{}:{}:{}",
-                self.screen,
-                self.visible,
-                self.managed.focused_window,
-                self.unmanaged.focused_window,
-                windows.join(":"))
-    }
-
-    fn all(&self) -> Vec<Window> {
+    pub fn all(&self) -> Vec<Window> {
         self.unmanaged.all().iter().chain(self.managed.all().iter()).copied().collect()
     }
 
@@ -199,7 +150,7 @@ impl Workspace {
         self.unmanaged.visible.iter().chain(self.managed.visible.iter()).copied().collect()
     }
 
-    fn all_urgent(&self) -> Vec<Window> {
+    pub fn all_urgent(&self) -> Vec<Window> {
         self.unmanaged.urgent.iter().chain(self.managed.urgent.iter()).copied().collect()
     }
 
@@ -215,6 +166,18 @@ impl Workspace {
         self.tag.clone()
     }
 
+    pub fn get_screen(&self) -> usize {
+        self.screen
+    }
+
+    pub fn get_managed(&self) -> &Stack {
+        &self.managed
+    }
+
+    pub fn get_unmanaged(&self) -> &Stack {
+        &self.unmanaged
+    }
+
     pub fn is_unmanaged(&self, window: Window) -> bool {
         self.unmanaged.contains(window)
     }
@@ -277,7 +240,7 @@ impl Workspace {
 
         self.managed.focused_window = 0;
         ws.unmap_window(window);
-        self.managed.remove(index);
+        self.managed.visible.remove(index);
 
         let new_focused_window = if !self.managed.visible.is_empty() {
             self.managed.visible[if index < self.managed.visible.len() {
@@ -301,7 +264,7 @@ impl Workspace {
 
         self.unmanaged.focused_window = 0;
         ws.unmap_window(window);
-        self.unmanaged.remove(index);
+        self.unmanaged.visible.remove(index);
 
         let new_focused_window = if !self.unmanaged.visible.is_empty() {
             self.unmanaged.visible[if index < self.unmanaged.visible.len() {
@@ -322,7 +285,13 @@ impl Workspace {
     }
 
     pub fn remove_window(&mut self, ws: &XlibWindowSystem, config: &Config, window: Window) {
-        if self.managed.contains(window) {
+        if self.managed.hidden.contains(&window) {
+            debug!("Remove Hidden: {}", window);
+            self.managed.hidden.retain(|&x| x != window);
+        } else if self.unmanaged.hidden.contains(&window) {
+            debug!("Remove Hidden: {}", window);
+            self.unmanaged.hidden.retain(|&x| x != window);
+        } else if self.managed.contains(window) {
             debug!("Remove Managed: {}", window);
             self.remove_managed(ws, config, window);
         } else if self.unmanaged.contains(window) {
@@ -339,6 +308,53 @@ impl Workspace {
         }
     }
 
+    // puts the window in place of the terminal and hides the terminal, floating windows don't swallow
+    fn swallow(&mut self, ws: &XlibWindowSystem, config: &Config, terminal: Window, window: Window) -> bool {
+        if !self.managed.visible.contains(&window) || !self.managed.visible.contains(&terminal) {
+            return false;
+        }
+
+        debug!("{} swallows {}", window, terminal);
+        self.managed.visible.retain(|&x| x != window);
+        let index = self.managed.index_of_visible(terminal);
+        self.managed.visible[index] = window;
+        self.managed.hidden.push(terminal);
+
+        if self.managed.focused_window == terminal {
+            self.managed.focused_window = 0;
+        }
+        ws.hide_window(terminal);
+
+        if self.visible {
+            self.redraw(ws, config);
+            self.focus_window(ws, config, window);
+        }
+
+        true
+    }
+
+    // shows a swallowed terminal again right after the window that is about to close
+    fn restore_swallowed(&mut self, ws: &XlibWindowSystem, config: &Config, terminal: Window, window: Window) {
+        let hidden = match self.managed.hidden.iter().position(|&x| x == terminal) {
+            Some(x) => x,
+            None => return,
+        };
+
+        debug!("restoring {} swallowed by {}", terminal, window);
+        self.managed.hidden.remove(hidden);
+
+        let index = self.managed.visible.iter()
+            .position(|&x| x == window)
+            .map(|x| x + 1)
+            .unwrap_or_else(|| self.managed.visible.len());
+        self.managed.visible.insert(index, terminal);
+
+        if self.visible {
+            ws.show_window(terminal);
+            self.redraw(ws, config);
+        }
+    }
+
     pub fn focus_window(&mut self, ws: &XlibWindowSystem, config: &Config, window: Window) {
         if window == 0 || self.unmanaged.focused_window == window || self.managed.focused_window == window {
             return;
@@ -431,8 +447,21 @@ impl Workspace {
         self.redraw(ws, config);
     }
 
+    /// Moves a managed window to `position` in the stack
+    pub fn place_window(&mut self, ws: &XlibWindowSystem, config: &Config, window: Window, position: usize) {
+        if let Some(index) = self.managed.visible.iter().position(|&x| x == window) {
+            self.managed.visible.remove(index);
+            let position = cmp::min(position, self.managed.visible.len());
+            self.managed.visible.insert(position, window);
+
+            if self.visible {
+                self.redraw(ws, config);
+            }
+        }
+    }
+
     pub fn contains(&self, window: Window) -> bool {
-        self.all().iter().any(|&w| w == window)
+        self.all().contains(&window)
     }
 
     pub fn unfocus(&mut self, ws: &XlibWindowSystem, config: &Config) {
@@ -520,88 +549,176 @@ impl Workspace {
 pub struct Workspaces {
     list: Vec<Workspace>,
     cur: usize,
-    screens: usize
+    screens: usize,
+    // windows and the terminals they swallowed
+    swallowed: Vec<(Window, Window)>,
 }
 
 impl Workspaces {
-    pub fn new(config: &Config, screens: usize, windows: &[Window]) -> Workspaces {
-        if Path::new(concat!(env!("HOME"), "/.xr3wm/.tmp")).exists() {
-            debug!("loading previous workspace state");
-            Workspaces::load_workspaces(config, screens, windows)
-        } else {
-            let mut workspaces = Workspaces {
-                list: config.workspaces
-                    .iter()
-                    .map(|c| {
-                        Workspace {
-                            tag: c.tag.clone(),
-                            screen: c.screen,
-                            layout: c.layout.copy(),
-                            ..Default::default()
-                        }
-                    })
-                    .collect(),
-                cur: 0,
-                screens
-            };
-
-            for screen in 0..screens {
-                if workspaces.list.iter().find(|ws| ws.screen == screen).is_none() {
-                    if let Some(ws) = workspaces.list.iter_mut().filter(|ws| ws.screen == 0).nth(1) {
-                        ws.screen = screen;
+    pub fn new(ws: &XlibWindowSystem, config: &Config) -> Workspaces {
+        let screens = ws.get_screen_infos().len();
+        let mut workspaces = Workspaces {
+            list: config.workspaces
+                .iter()
+                .map(|c| {
+                    Workspace {
+                        tag: c.tag.clone(),
+                        // screens configured for another setup fall back to the first one
+                        screen: if c.screen < screens { c.screen } else { 0 },
+                        layout: c.layout.copy(),
+                        ..Default::default()
                     }
+                })
+                .collect(),
+            cur: 0,
+            screens,
+            swallowed: Vec::new(),
+        };
+
+        // older builds don't mark their restarts, their state file is only written on restart though
+        let path = Some(paths::workspace_state())
+            .filter(|x| x.exists())
+            .or_else(|| Some(paths::legacy_workspace_state()).filter(|x| x.exists()));
+        if let Some(path) = path {
+            debug!("loading previous workspace state from {}", path.display());
+
+            match SavedState::load(&path) {
+                Ok(state) => workspaces.restore(ws, &state),
+                Err(e) => {
+                    let msg = e.iter_chain().map(|x| x.to_string()).collect::<Vec<String>>().join(": ");
+                    warn!("starting with fresh workspaces: {}", msg);
+                }
+            }
+
+            remove_file(&path).ok();
+        }
+
+        workspaces.assign_screens();
+        workspaces
+    }
+
+    // fills the workspaces with the windows of the saved state that still exist,
+    // saved workspaces are matched by tag and by position if the tag is unknown,
+    // every saved workspace is used at most once
+    fn restore(&mut self, ws: &XlibWindowSystem, state: &SavedState) {
+        let windows = ws.get_windows();
+        let screens = ws.get_screen_infos();
+        let tags: Vec<String> = self.list.iter().map(|x| x.tag.clone()).collect();
+        let mut used = vec![false; state.workspaces.len()];
+
+        let mut matches: Vec<Option<usize>> = self.list.iter()
+            .map(|workspace| {
+                let index = state.workspaces.iter()
+                    .enumerate()
+                    .position(|(j, x)| !used[j] && x.tag == workspace.tag);
+                if let Some(j) = index {
+                    used[j] = true;
                 }
+                index
+            })
+            .collect();
+
+        for (i, index) in matches.iter_mut().enumerate().filter(|(_, x)| x.is_none()) {
+            if i < state.workspaces.len() && !used[i] && !tags.contains(&state.workspaces[i].tag) {
+                used[i] = true;
+                *index = Some(i);
             }
+        }
 
-            for screen in 0..screens {
-                let ws = workspaces.list.iter_mut().find(|ws| ws.screen == screen).unwrap();
-                ws.visible = true;
+        for (workspace, index) in self.list.iter_mut().zip(matches) {
+            let saved = match index {
+                Some(x) => &state.workspaces[x],
+                None => continue,
+            };
+
+            debug!("restoring workspace {}", workspace.tag);
+            workspace.screen = if saved.screen < self.screens { saved.screen } else { 0 };
+            workspace.visible = saved.visible && saved.screen < self.screens;
+            workspace.managed = Stack::restore(&saved.managed, &windows);
+            workspace.unmanaged = Stack::restore(&saved.unmanaged, &windows);
+
+            if let Some(ref layout) = saved.layout {
+                workspace.layout.import_state(layout);
             }
 
-            workspaces
+            for geometry in saved.floating.iter().filter(|x| windows.contains(&x.window)) {
+                ws.move_resize_window(geometry.window, geometry.x, geometry.y, geometry.width, geometry.height);
+            }
+
+            // tiled fullscreen windows are laid out as usual, floating ones cover their screen again
+            for &window in saved.fullscreen.iter().filter(|x| windows.contains(x)) {
+                ws.set_fullscreen(window);
+                if let (true, Some(screen)) = (workspace.unmanaged.contains(window), screens.get(workspace.screen)) {
+                    ws.move_resize_window(window, screen.x, screen.y, screen.width, screen.height);
+                }
+            }
+        }
+
+        if state.current < self.list.len() {
+            self.cur = state.current;
         }
+
+        self.swallowed = state.swallowed.iter()
+            .filter(|&&(w, t)| windows.contains(&w) && windows.contains(&t))
+            .cloned()
+            .collect();
     }
 
-    fn load_workspaces(config: &Config, screens: usize, windows: &[Window]) -> Workspaces {
-        let path = Path::new(concat!(env!("HOME"), "/.xr3wm/.tmp"));
+    // makes sure every screen shows exactly one workspace and the current one is visible
+    fn assign_screens(&mut self) {
+        for screen in 0..self.screens {
+            let mut shown = self.list.iter_mut().filter(|x| x.visible && x.screen == screen);
+            if shown.next().is_some() {
+                for workspace in shown {
+                    workspace.visible = false;
+                }
+                continue;
+            }
 
-        let mut file = BufReader::new(File::open(&path).unwrap());
-        let mut cur = String::new();
-        file.read_line(&mut cur);
-        let lines: Vec<String> = file.lines().map(|x| x.unwrap()).collect();
+            let index = self.list.iter().position(|x| !x.visible && x.screen == screen)
+                .or_else(|| self.list.iter().position(|x| !x.visible));
 
-        remove_file(&path).ok();
+            if let Some(index) = index {
+                self.list[index].screen = screen;
+                self.list[index].visible = true;
+            }
+        }
 
-        Workspaces {
-            list: config.workspaces
+        if !self.list[self.cur].visible {
+            self.cur = self.list.iter().position(|x| x.visible).unwrap_or(0);
+        }
+    }
+
+    /// State saved before restarting, restored by `Workspaces::new`
+    pub fn save_state(&self, ws: &XlibWindowSystem) -> SavedState {
+        SavedState {
+            version: state::VERSION,
+            current: self.cur,
+            workspaces: self.list
                 .iter()
-                .enumerate()
-                .map(|(i, c)| {
-                    if i < lines.len() {
-                        debug!("loading workspace {}", i + 1);
-
-                        Workspace::new(&c.tag, c.layout.copy(), windows, lines.get(i).unwrap()).unwrap()
-                    } else {
-                        Workspace {
-                            tag: c.tag.clone(),
-                            screen: c.screen,
-                            layout: c.layout.copy(),
-                            ..Default::default()
-                        }
+                .map(|x| {
+                    SavedWorkspace {
+                        tag: x.tag.clone(),
+                        screen: x.screen,
+                        visible: x.visible,
+                        managed: x.managed.save(),
+                        unmanaged: x.unmanaged.save(),
+                        layout: x.layout.export_state(),
+                        floating: x.unmanaged.all()
+                            .into_iter()
+                            .map(|w| SavedGeometry::new(w, ws.get_geometry(w)))
+                            .collect(),
+                        fullscreen: x.all()
+                            .into_iter()
+                            .filter(|&w| ws.is_fullscreen(w))
+                            .collect(),
                     }
                 })
                 .collect(),
-            cur: cur[..cur.len() - 1].parse::<usize>().unwrap(),
-            screens
+            swallowed: self.swallowed.clone(),
         }
     }
 
-    pub fn serialize(&self) -> String {
-        format!("{}\n{}",
-                self.cur,
-                self.list.iter().map(|x| x.serialize()).collect::<Vec<String>>().join("\n"))
-    }
-
     pub fn get(&self, index: usize) -> &Workspace {
         if index < self.list.len() {
             self.list.get(index).unwrap()
@@ -711,7 +828,7 @@ impl Workspaces {
             .enumerate()
             .filter(|&(i, workspace)| workspace.screen == screen && workspace.visible && i != self.cur)
             .map(|(i, _)| i)
-            .last();
+            .next_back();
 
         if let Some(idx) = idx_workspace {
             self.list[self.cur].unfocus(ws, config);
@@ -745,6 +862,36 @@ impl Workspaces {
         if let Some(workspace) = self.find_window(window) {
             workspace.remove_window(ws, config, window);
         }
+
+        // a swallowed terminal closed while hidden
+        self.swallowed.retain(|&(_, t)| t != window);
+    }
+
+    /// Adds a window in place of the terminal it was started from, the terminal is hidden
+    /// until the window closes
+    pub fn swallow(&mut self, ws: &XlibWindowSystem, config: &Config, terminal: Window, window: Window) {
+        let index = self.list.iter().position(|x| x.managed.visible.contains(&terminal));
+        self.add_window(index, ws, config, window);
+
+        if let Some(index) = index {
+            if self.list[index].swallow(ws, config, terminal, window) {
+                self.swallowed.push((window, terminal));
+            }
+        }
+    }
+
+    /// Brings back the terminal swallowed by a window that is closing, has to be called
+    /// before the window is removed
+    pub fn unswallow(&mut self, ws: &XlibWindowSystem, config: &Config, window: Window) {
+        let index = match self.swallowed.iter().position(|&(w, _)| w == window) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let (_, terminal) = self.swallowed.remove(index);
+        if let Some(workspace) = self.find_window(terminal) {
+            workspace.restore_swallowed(ws, config, terminal, window);
+        }
     }
 
     pub fn hide_window(&mut self, window: Window) {
@@ -781,6 +928,68 @@ impl Workspaces {
         self.list.iter_mut().find(|ws| ws.screen == 0).unwrap().show(ws, config);
     }
 
+    /// Applies a reloaded config to the running workspaces.
+    ///
+    /// Windows of removed workspaces move to the last remaining one, layouts are only replaced
+    /// if they changed in the config so adjustments made at runtime survive the reload.
+    pub fn reconfigure(&mut self, ws: &XlibWindowSystem, old: &Config, config: &Config) {
+        let len = config.workspaces.len();
+
+        if self.list.len() > len {
+            let removed: Vec<Workspace> = self.list.drain(len..).collect();
+
+            for mut workspace in removed.into_iter() {
+                debug!("removing workspace {}", workspace.tag);
+
+                if workspace.visible {
+                    workspace.hide(ws);
+
+                    // keep the screen occupied
+                    if let Some(other) = self.list.iter_mut().find(|x| !x.visible) {
+                        other.screen = workspace.screen;
+                        other.visible = true;
+                    }
+                }
+
+                for window in workspace.all() {
+                    self.list[len - 1].add_window(ws, config, window);
+                }
+            }
+
+            if self.cur >= len {
+                self.cur = self.list.iter()
+                    .position(|x| x.visible)
+                    .unwrap_or(0);
+            }
+        }
+
+        for (i, c) in config.workspaces.iter().enumerate() {
+            if let Some(workspace) = self.list.get_mut(i) {
+                workspace.tag = c.tag.clone();
+
+                let old_layout = old.workspaces.get(i).map(|x| x.layout.describe());
+                if old_layout != Some(c.layout.describe()) {
+                    debug!("replacing layout of workspace {}", c.tag);
+                    workspace.layout = c.layout.copy();
+                }
+            } else {
+                debug!("adding workspace {}", c.tag);
+                self.list.push(Workspace {
+                    tag: c.tag.clone(),
+                    screen: if c.screen < self.screens { c.screen } else { 0 },
+                    layout: c.layout.copy(),
+                    ..Default::default()
+                });
+            }
+        }
+
+        // borders and layouts of the visible workspaces, the hidden ones are redrawn when shown
+        for workspace in self.list.iter_mut().filter(|x| x.visible) {
+            workspace.show(ws, config);
+        }
+        self.list[self.cur].focus(ws, config);
+    }
+
     pub fn find_window(&mut self, window: Window) -> Option<&mut Workspace> {
         self.list.iter_mut().find(|workspace| workspace.contains(window))
     }
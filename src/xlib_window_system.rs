@@ -1,5 +1,6 @@
 #![allow(non_upper_case_globals, unused_variables, dead_code)]
-#![allow(clippy::too_many_arguments)]
+// the widths of the C types differ between platforms, casts between them stay explicit
+#![allow(clippy::too_many_arguments, clippy::unnecessary_cast)]
 
 extern crate libc;
 
@@ -11,20 +12,176 @@ use std::env;
 use std::default::Default;
 use std::ptr::null_mut;
 use std::mem::MaybeUninit;
+use std::os::unix::io::RawFd;
+use std::process;
 use std::slice::from_raw_parts;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::ffi::{CStr, CString};
 use self::libc::{c_void, c_uchar, c_int, c_uint, c_long, c_ulong};
 use self::libc::malloc;
 use self::XlibEvent::*;
+use failure::{Error, bail};
 use xinerama::XineramaQueryScreens;
 use xlib::*;
 
-extern "C" fn error_handler(display: *mut Display, event: *mut XErrorEvent) -> c_int {
-    // TODO: proper error handling
-    // HACK: fixes LeaveNotify on invalid windows
+// not covered by the xlib bindings
+#[link(name = "X11")]
+extern "C" {
+    fn XFree(data: *mut c_void) -> c_int;
+    fn XSetIOErrorHandler(handler: extern "C" fn(*mut Display) -> c_int) -> *mut u8;
+    fn XDefaultScreen(display: *mut Display) -> c_int;
+    fn XGetSelectionOwner(display: *mut Display, selection: Atom) -> Window;
+    fn XSetSelectionOwner(display: *mut Display, selection: Atom, owner: Window, time: Time) -> c_int;
+    fn XCreateSimpleWindow(display: *mut Display, parent: Window, x: c_int, y: c_int, width: c_uint, height: c_uint,
+                           border_width: c_uint, border: c_ulong, background: c_ulong) -> Window;
+    fn XWindowEvent(display: *mut Display, window: Window, mask: c_long, event: *mut c_void) -> c_int;
+    fn XCheckWindowEvent(display: *mut Display, window: Window, mask: c_long, event: *mut c_void) -> c_int;
+}
+
+const BadWindow: u8 = 3;
+const BadDrawable: u8 = 9;
+const BadAccess: u8 = 10;
+
+const SelectionClear: c_int = 29;
+const StructureNotifyMask: c_long = 1 << 17;
+const PropertyChangeMask: c_long = 1 << 22;
+const RootEventMask: c_long = 0x001A_0034;
+
+#[repr(C)]
+struct XSelectionClearEvent {
+    _type: c_int,
+    serial: c_ulong,
+    send_event: c_int,
+    display: *mut Display,
+    window: Window,
+    selection: Atom,
+    time: Time,
+}
+
+/// Time the replaced window manager gets to quit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// XClientMessageEvent with the `long` data of the MANAGER message, padded to the size of XEvent
+#[repr(C)]
+struct ManagerEvent {
+    _type: c_int,
+    serial: c_ulong,
+    send_event: c_int,
+    display: *mut Display,
+    window: Window,
+    message_type: Atom,
+    format: c_int,
+    data: [c_long; 5],
+    pad: [c_long; 12],
+}
+
+/// Error reported by the X server for one of our requests
+#[derive(Clone, Copy)]
+pub struct XError {
+    pub code: u8,
+    pub request: u8,
+    pub resource: Window,
+}
+
+impl XError {
+    /// Whether the resource of the request doesn't exist anymore
+    pub fn is_gone(&self) -> bool {
+        self.code == BadWindow || self.code == BadDrawable
+    }
+}
+
+// errors are only collected in the handler, xlib must not be called from within it
+static ERRORS: Mutex<Vec<XError>> = Mutex::new(Vec::new());
+
+type ConnectionLost = Box<dyn FnOnce() + Send>;
+static CONNECTION_LOST: Mutex<Option<ConnectionLost>> = Mutex::new(None);
+
+// names of the core protocol errors, xlib can't be asked for them from within the error handler
+fn error_name(code: u8) -> String {
+    match code {
+        1 => "BadRequest",
+        2 => "BadValue",
+        3 => "BadWindow",
+        4 => "BadPixmap",
+        5 => "BadAtom",
+        6 => "BadCursor",
+        7 => "BadFont",
+        8 => "BadMatch",
+        9 => "BadDrawable",
+        10 => "BadAccess",
+        11 => "BadAlloc",
+        12 => "BadColor",
+        13 => "BadGC",
+        14 => "BadIDChoice",
+        15 => "BadName",
+        16 => "BadLength",
+        17 => "BadImplementation",
+        x => return format!("error {}", x),
+    }.to_string()
+}
+
+// names of the core protocol requests xr3wm sends
+fn request_name(code: u8) -> String {
+    match code {
+        2 => "ChangeWindowAttributes",
+        3 => "GetWindowAttributes",
+        8 => "MapWindow",
+        10 => "UnmapWindow",
+        12 => "ConfigureWindow",
+        14 => "GetGeometry",
+        15 => "QueryTree",
+        18 => "ChangeProperty",
+        20 => "GetProperty",
+        25 => "SendEvent",
+        28 => "GrabButton",
+        33 => "GrabKey",
+        34 => "UngrabKey",
+        41 => "WarpPointer",
+        42 => "SetInputFocus",
+        x => return format!("request {}", x),
+    }.to_string()
+}
+
+extern "C" fn error_handler(_: *mut Display, event: *mut XErrorEvent) -> c_int {
+    let event = unsafe { &*event };
+    let error = XError {
+        code: event.error_code,
+        request: event.request_code,
+        resource: event.resourceid as Window,
+    };
+
+    let text = error_name(error.code);
+
+    // windows being destroyed while events about them are still queued are expected
+    if error.is_gone() {
+        debug!("X error: {} in {}.{} for {:#x}", text, request_name(error.request), event.minor_code, error.resource);
+    } else {
+        warn!("X error: {} in {}.{} for {:#x}", text, request_name(error.request), event.minor_code, error.resource);
+    }
+
+    if let Ok(mut errors) = ERRORS.lock() {
+        errors.push(error);
+    }
+
     0
 }
 
+// xlib exits the process once this returns, so cleaning up like stopping the statusbar has to
+// happen here
+extern "C" fn io_error_handler(_: *mut Display) -> c_int {
+    error!("lost the connection to the X server, shutting down");
+
+    let cleanup = CONNECTION_LOST.lock().ok().and_then(|mut x| x.take());
+    if let Some(cleanup) = cleanup {
+        cleanup();
+    }
+
+    ::log::logger().flush();
+    process::exit(1)
+}
+
 pub struct XlibWindowSystem {
     display: *mut Display,
     root: Window,
@@ -42,6 +199,8 @@ pub enum XlibEvent {
     XFocusOut(Window),
     XKeyPress(Window, u8, String),
     XButtonPress(Window),
+    /// Another window manager took over the screen
+    XSelectionClear(Window),
     Ignored,
 }
 
@@ -73,9 +232,9 @@ impl XlibWindowSystem {
             }
 
             let root = XDefaultRootWindow(display);
-            XSelectInput(display, root, 0x001A_0034);
             XDefineCursor(display, root, XCreateFontCursor(display, 68));
             XSetErrorHandler(error_handler as *mut u8);
+            XSetIOErrorHandler(io_error_handler);
 
             XlibWindowSystem {
                 display,
@@ -91,6 +250,128 @@ impl XlibWindowSystem {
         }
     }
 
+    pub fn get_display_name(&self) -> String {
+        unsafe {
+            let name = XDisplayString(self.display);
+            if name.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(name).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    /// Becomes the window manager of the screen by taking the ICCCM `WM_Sn` manager selection
+    /// and redirecting the requests of the root window.
+    ///
+    /// Fails if another window manager is running, unless `replace` is set and it supports
+    /// being replaced through the selection.
+    pub fn manage_screen(&self, replace: bool) -> Result<(), Error> {
+        let name = format!("WM_S{}", unsafe { XDefaultScreen(self.display) });
+        let selection = self.get_atom(&name);
+
+        unsafe {
+            let owner = XGetSelectionOwner(self.display, selection);
+            if owner != 0 {
+                if !replace {
+                    bail!("another window manager is running, use --replace to replace it");
+                }
+
+                info!("replacing the running window manager");
+                XSelectInput(self.display, owner, StructureNotifyMask);
+            }
+
+            // the selection needs a timestamp, it is taken from a property change on our own window
+            let window = XCreateSimpleWindow(self.display, self.root, -1, -1, 1, 1, 0, 0, 0);
+            XSelectInput(self.display, window, PropertyChangeMask);
+            let title = "xr3wm";
+            XChangeProperty(self.display,
+                            window,
+                            self.get_atom("_NET_WM_NAME"),
+                            self.get_atom("UTF8_STRING"),
+                            8,
+                            0,
+                            title.as_ptr() as *mut c_uchar,
+                            title.len() as c_int);
+            XWindowEvent(self.display, window, PropertyChangeMask, self.event);
+            let time = self.cast_event_to::<XPropertyEvent>().time;
+
+            XSetSelectionOwner(self.display, selection, window, time);
+            if XGetSelectionOwner(self.display, selection) != window {
+                bail!("failed to acquire the {} selection", name);
+            }
+
+            if owner != 0 {
+                let start = Instant::now();
+                loop {
+                    if XCheckWindowEvent(self.display, owner, StructureNotifyMask, self.event) != 0 &&
+                        *self.cast_event_to::<c_int>() == DestroyNotify {
+                        break;
+                    }
+
+                    if start.elapsed() > REPLACE_TIMEOUT {
+                        warn!("the replaced window manager didn't quit in time");
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+
+            let mut event = ManagerEvent {
+                _type: 33,
+                serial: 0,
+                send_event: 0,
+                display: self.display,
+                window: self.root,
+                message_type: self.get_atom("MANAGER"),
+                format: 32,
+                data: [time as c_long, selection as c_long, window as c_long, 0, 0],
+                pad: [0; 12],
+            };
+            XSendEvent(self.display, self.root, 0, StructureNotifyMask, &mut event as *mut _ as *mut c_void);
+
+            XSelectInput(self.display, self.root, RootEventMask);
+            XSync(self.display, 0);
+        }
+
+        // only one client can redirect the requests of the root window
+        if self.take_errors().iter().any(|x| x.code == BadAccess && x.resource == self.root) {
+            bail!("another window manager is running and can't be replaced");
+        }
+
+        Ok(())
+    }
+
+    /// Sets what to clean up before exiting if the connection to the X server is lost
+    pub fn on_connection_lost<F: FnOnce() + Send + 'static>(&self, f: F) {
+        if let Ok(mut x) = CONNECTION_LOST.lock() {
+            *x = Some(Box::new(f));
+        }
+    }
+
+    /// Errors received since the last call, errors arrive asynchronously while handling events
+    pub fn take_errors(&self) -> Vec<XError> {
+        ERRORS.lock()
+            .map(|mut x| x.drain(..).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn window_exists(&self, window: Window) -> bool {
+        unsafe {
+            let mut attributes = MaybeUninit::<XWindowAttributes>::uninit();
+            XGetWindowAttributes(self.display, window, attributes.as_mut_ptr()) != 0
+        }
+    }
+
+    pub fn get_connection_fd(&self) -> RawFd {
+        unsafe { XConnectionNumber(self.display) }
+    }
+
+    // flushes the output buffer and checks for queued or newly arrived events
+    pub fn has_pending_events(&self) -> bool {
+        unsafe { XPending(self.display) > 0 }
+    }
+
     pub fn setup_window(&self,
                         x: u32,
                         y: u32,
@@ -114,31 +395,35 @@ impl XlibWindowSystem {
             let mut ret_format: c_int = 0;
             let mut ret_nitems: c_ulong = 0;
             let mut ret_bytes_after: c_ulong = 0;
-            let mut ret_prop = MaybeUninit::<*mut c_ulong>::uninit();
-
-            if XGetWindowProperty(self.display,
-                                  window,
-                                  property,
-                                  0,
-                                  0xFFFF_FFFF,
-                                  0,
-                                  0,
-                                  &mut ret_type,
-                                  &mut ret_format,
-                                  &mut ret_nitems,
-                                  &mut ret_bytes_after,
-                                  ret_prop.as_mut_ptr() as *mut *mut c_uchar) == 0 {
-                if ret_format != 0 {
-                    Some(from_raw_parts(ret_prop.assume_init() as *const c_ulong, ret_nitems as usize)
-                        .iter()
-                        .map(|&x| x as u64)
-                        .collect())
-                } else {
-                    None
-                }
+            let mut ret_prop: *mut c_ulong = null_mut();
+
+            let status = XGetWindowProperty(self.display,
+                                            window,
+                                            property,
+                                            0,
+                                            0xFFFF_FFFF,
+                                            0,
+                                            0,
+                                            &mut ret_type,
+                                            &mut ret_format,
+                                            &mut ret_nitems,
+                                            &mut ret_bytes_after,
+                                            &mut ret_prop as *mut *mut c_ulong as *mut *mut c_uchar);
+
+            let result = if status == 0 && ret_format != 0 && !ret_prop.is_null() {
+                Some(from_raw_parts(ret_prop as *const c_ulong, ret_nitems as usize)
+                    .iter()
+                    .map(|&x| x as u64)
+                    .collect())
             } else {
                 None
+            };
+
+            if !ret_prop.is_null() {
+                XFree(ret_prop as *mut c_void);
             }
+
+            result
         }
     }
 
@@ -174,6 +459,43 @@ impl XlibWindowSystem {
         }
     }
 
+    /// Top level windows that are mapped and not override-redirect, used to adopt the windows
+    /// that existed before xr3wm started
+    pub fn get_mapped_windows(&self) -> Vec<Window> {
+        self.get_windows()
+            .into_iter()
+            .filter(|&window| unsafe {
+                let mut attributes = MaybeUninit::<XWindowAttributes>::uninit();
+                if XGetWindowAttributes(self.display, window, attributes.as_mut_ptr()) == 0 {
+                    return false;
+                }
+
+                let attributes = attributes.assume_init();
+                // map_state 2 is IsViewable
+                attributes.override_redirect == 0 && attributes.map_state == 2
+            })
+            .collect()
+    }
+
+    /// Desktop index a previous EWMH compliant window manager stored in `_NET_WM_DESKTOP`
+    pub fn get_desktop(&self, window: Window) -> Option<usize> {
+        self.get_property(window, self.get_atom("_NET_WM_DESKTOP"))
+            .and_then(|x| x.first().cloned())
+            // 0xFFFFFFFF means all desktops
+            .filter(|&x| x != 0xFFFF_FFFF)
+            .map(|x| x as usize)
+    }
+
+    /// Selects the events xr3wm needs for a window it starts managing
+    pub fn setup_managed_window(&self, window: Window) {
+        unsafe {
+            let atom = self.get_atom("WM_STATE");
+            self.change_property(window as u64, atom, atom, 0, &mut [1, 0]);
+            self.grab_button(window);
+            XSelectInput(self.display, window, 0x0042_0010);
+        }
+    }
+
     pub fn get_strut(&self, screen: Rect) -> Strut {
         let atom = self.get_atom("_NET_WM_STRUT_PARTIAL");
 
@@ -229,6 +551,20 @@ impl XlibWindowSystem {
         }
     }
 
+    pub fn set_root_property_string(&self, property: &str, value: &str) {
+        unsafe {
+            let mut data = value.as_bytes().to_vec();
+            XChangeProperty(self.display,
+                            self.root,
+                            self.get_atom(property) as c_ulong,
+                            self.get_atom("UTF8_STRING") as c_ulong,
+                            8,
+                            0,
+                            data.as_mut_ptr(),
+                            data.len() as c_int);
+        }
+    }
+
     pub fn configure_window(&self,
                             window: Window,
                             window_changes: WindowChanges,
@@ -380,7 +716,7 @@ impl XlibWindowSystem {
                 debug!("{}", w);
             }
             XRestackWindows(self.display,
-                            (&mut windows[..]).as_mut_ptr(),
+                            windows.as_mut_ptr(),
                             windows.len() as i32);
         }
     }
@@ -418,6 +754,14 @@ impl XlibWindowSystem {
         }
     }
 
+    pub fn ungrab_modifier(&self, mod_key: u8) {
+        unsafe {
+            for &mods in [0, MOD_2, MOD_LOCK, MOD_2 | MOD_LOCK].iter() {
+                XUngrabKey(self.display, 0, u32::from(mod_key | mods), self.root);
+            }
+        }
+    }
+
     pub fn keycode_to_string(&self, keycode: u32) -> String {
         unsafe {
             let keysym = XKeycodeToKeysym(self.display, keycode as u8, 0);
@@ -536,6 +880,34 @@ impl XlibWindowSystem {
         }
     }
 
+    pub fn is_fullscreen(&self, window: Window) -> bool {
+        let fullscreen = self.get_atom("_NET_WM_STATE_FULLSCREEN");
+
+        self.get_property(window, self.get_atom("_NET_WM_STATE"))
+            .map(|x| x.contains(&fullscreen))
+            .unwrap_or(false)
+    }
+
+    /// Adds `_NET_WM_STATE_FULLSCREEN` to the state of the window unless it is already set
+    pub fn set_fullscreen(&self, window: Window) {
+        if self.is_fullscreen(window) {
+            return;
+        }
+
+        let mut atom = self.get_atom("_NET_WM_STATE_FULLSCREEN") as c_ulong;
+        unsafe {
+            // type XA_ATOM, mode PropModeAppend
+            XChangeProperty(self.display,
+                            window,
+                            self.get_atom("_NET_WM_STATE") as c_ulong,
+                            4,
+                            32,
+                            2,
+                            &mut atom as *mut c_ulong as *mut c_uchar,
+                            1);
+        }
+    }
+
     pub fn transient_for(&self, window: Window) -> Option<Window> {
         unsafe {
             let mut w = MaybeUninit::uninit();
@@ -599,6 +971,78 @@ impl XlibWindowSystem {
         }
     }
 
+    pub fn get_instance_name(&self, window: Window) -> String {
+        unsafe {
+            let mut hint = MaybeUninit::uninit();
+
+            if XGetClassHint(self.display, window, hint.as_mut_ptr()) != 0 {
+                let hint = hint.assume_init();
+                if !hint.res_name.is_null() {
+                    return match str::from_utf8(CStr::from_ptr(hint.res_name).to_bytes()) {
+                        Ok(s) => s.to_string(),
+                        Err(_) => String::new(),
+                    }
+                }
+            }
+            String::new()
+        }
+    }
+
+    /// `WM_WINDOW_ROLE` set by applications to tell their windows apart across sessions
+    pub fn get_window_role(&self, window: Window) -> Option<String> {
+        self.get_string_property(window, "WM_WINDOW_ROLE")
+    }
+
+    /// `_NET_STARTUP_ID` taken over from `DESKTOP_STARTUP_ID` by applications supporting
+    /// startup notification
+    pub fn get_startup_id(&self, window: Window) -> Option<String> {
+        self.get_string_property(window, "_NET_STARTUP_ID")
+    }
+
+    pub fn get_window_pid(&self, window: Window) -> Option<u32> {
+        self.get_property(window, self.get_atom("_NET_WM_PID"))
+            .and_then(|x| x.first().cloned())
+            .map(|x| x as u32)
+    }
+
+    fn get_string_property(&self, window: Window, property: &str) -> Option<String> {
+        unsafe {
+            let mut ret_type: c_ulong = 0;
+            let mut ret_format: c_int = 0;
+            let mut ret_nitems: c_ulong = 0;
+            let mut ret_bytes_after: c_ulong = 0;
+            let mut ret_prop: *mut c_uchar = null_mut();
+
+            let status = XGetWindowProperty(self.display,
+                                            window,
+                                            self.get_atom(property),
+                                            0,
+                                            0xFFFF_FFFF,
+                                            0,
+                                            0,
+                                            &mut ret_type,
+                                            &mut ret_format,
+                                            &mut ret_nitems,
+                                            &mut ret_bytes_after,
+                                            &mut ret_prop);
+
+            let result = if status == 0 && ret_format == 8 && !ret_prop.is_null() {
+                let bytes = from_raw_parts(ret_prop as *const u8, ret_nitems as usize);
+                str::from_utf8(bytes).ok()
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string())
+            } else {
+                None
+            };
+
+            if !ret_prop.is_null() {
+                XFree(ret_prop as *mut c_void);
+            }
+
+            result
+        }
+    }
+
     pub fn get_window_title(&self, window: Window) -> String {
         if window == self.root {
             return String::new();
@@ -659,13 +1103,7 @@ impl XlibWindowSystem {
         match evt_type {
             MapRequest => {
                 let evt: &XMapRequestEvent = self.cast_event_to();
-
-                unsafe {
-                    let atom = self.get_atom("WM_STATE");
-                    self.change_property(evt.window as u64, atom, atom, 0, &mut [1, 0]);
-                    self.grab_button(evt.window);
-                    XSelectInput(self.display, evt.window, 0x0042_0010);
-                }
+                self.setup_managed_window(evt.window);
 
                 XMapRequest(evt.window)
             }
@@ -732,6 +1170,10 @@ impl XlibWindowSystem {
                           evt.state as u8,
                           self.keycode_to_string(evt.keycode))
             }
+            SelectionClear => {
+                let evt: &XSelectionClearEvent = self.cast_event_to();
+                XSelectionClear(evt.window)
+            }
             _ => Ignored,
         }
     }
@@ -6,16 +6,34 @@ extern crate clap;
 extern crate libloading;
 extern crate xlib;
 extern crate xinerama;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
 
-use clap::{Arg, App, ArgMatches};
+use clap::{Arg, App, ArgMatches, SubCommand};
 use clap::AppSettings::*;
-use failure::{ResultExt, Error, Fail};
-use config::Config;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::time::Duration;
+use failure::{ResultExt, Error, Fail, bail};
+use commands::Cmd;
+use config::{Config, Severity, Statusbar};
+use events::{Event, ReloadChange, State};
+use tree::Tree;
+use ipc::{IpcServer, Reply, SOCKET_ENV};
+use launch::Launcher;
+use autostart::Autostart;
+use reload::Reloader;
+use session::Session;
+use watch::ConfigWatcher;
 use workspaces::Workspaces;
-use xlib_window_system::XlibWindowSystem;
+use xlib::Window;
+use xlib_window_system::{XlibWindowSystem, XlibEvent};
 use xlib_window_system::XlibEvent::{XMapRequest, XConfigurationNotify, XConfigurationRequest,
                                     XDestroy, XUnmapNotify, XPropertyNotify, XEnterNotify,
-                                    XFocusOut, XKeyPress, XButtonPress};
+                                    XFocusOut, XKeyPress, XButtonPress, XSelectionClear};
 
 mod config;
 mod keycode;
@@ -23,9 +41,31 @@ mod commands;
 mod xlib_window_system;
 mod workspaces;
 mod layout;
+mod abi;
+mod paths;
+mod toml_config;
 mod utils;
+mod ipc;
+mod events;
+mod tree;
+mod state;
+mod client;
+mod reload;
+mod watch;
+mod session;
+mod crash;
+mod spawn;
+mod autostart;
+mod procfs;
+mod launch;
+mod swallow;
+mod source_hash;
 
 fn process_cli<'a>() -> ArgMatches<'a> {
+    cli().get_matches()
+}
+
+fn cli<'a, 'b>() -> App<'a, 'b> {
     App::new("xr3wm")
         .version("0.0.1")
         .author("Cristian Kubis <cristian.kubis@tsunix.de>")
@@ -36,11 +76,45 @@ fn process_cli<'a>() -> ArgMatches<'a> {
              .long("verbose")
              .multiple(true)
              .help("increrases the logging verbosity each use for up to 2 times"))
-        .get_matches()
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("config file to use instead of the default config.toml or config.rs"))
+        .arg(Arg::with_name("offline")
+             .long("offline")
+             .help("builds the config without network access, same as setting XR3WM_OFFLINE"))
+        .arg(Arg::with_name("replace")
+             .long("replace")
+             .help("replaces the running window manager"))
+        .arg(Arg::with_name("check-config")
+             .long("check-config")
+             .help("compiles or parses the config, validates it and exits without connecting to X"))
+        .subcommand(SubCommand::with_name("msg")
+                    .about("sends a command to the running instance")
+                    // options and `--` belong to the command, not to clap
+                    .setting(TrailingVarArg)
+                    .arg(Arg::with_name("command")
+                         .required(true)
+                         .multiple(true)
+                         .allow_hyphen_values(true)
+                         .help("command to run, e.g. 'switch-workspace 3'")))
+        .subcommand(SubCommand::with_name("query")
+                    .about("prints the state of the running instance as JSON")
+                    .arg(Arg::with_name("what")
+                         .required(true)
+                         .possible_values(&["tree", "workspaces", "windows"])))
+        .subcommand(SubCommand::with_name("subscribe")
+                    .about("prints events of the running instance as JSON lines")
+                    .arg(Arg::with_name("events")
+                         .multiple(true)
+                         .possible_values(&events::EVENT_KINDS)
+                         .help("events to subscribe to, defaults to all")))
 }
 
 // initialization of the logging system
-fn init_logger(verbosity: u64, logfile: &str) -> Result<(), Error> {
+fn init_logger(verbosity: u64, logfile: &Path) -> Result<(), Error> {
     fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!("[{}] {}", record.level(), message))
@@ -71,119 +145,527 @@ fn init_logger(verbosity: u64, logfile: &str) -> Result<(), Error> {
 fn run() -> Result<(), Error> {
     let matches = process_cli();
 
+    // subcommands act as ipc client of an already running instance
+    if matches.subcommand_name().is_some() {
+        let success = client::run(&matches)?;
+        ::std::process::exit(if success { 0 } else { 1 });
+    }
+
     let verbosity = matches.occurrences_of("verbose");
 
+    if matches.is_present("offline") {
+        ::std::env::set_var("XR3WM_OFFLINE", "1");
+    }
+
+    if let Some(path) = matches.value_of("config") {
+        let path = ::std::env::current_dir()
+            .map(|x| x.join(path))
+            .context("failed to resolve config path")?;
+        ::std::env::set_var(paths::CONFIG_ENV, path);
+    }
+
+    if matches.is_present("check-config") {
+        let valid = check_config()?;
+        ::std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    ::std::fs::create_dir_all(paths::state_dir())
+        .context("failed to create state directory")?;
+
     // initialize logging system
-    if let Err(e) = init_logger(verbosity, concat!(env!("HOME"), "/.xr3wm/xr3wm.log")) {
+    if let Err(e) = init_logger(verbosity, &paths::log_file()) {
         eprintln!("[ERROR] failed to initialize logging system: {}", e);
         ::std::process::exit(1);
     }
 
-    info!("loading config");
-    let mut config = Config::load()
-        .map_err(|e| {
-            let error = utils::concat_error_chain(&e);
-            utils::xmessage(&format!("failed to load config:\n{}", error))
-                .map_err(|e| warn!("failed to run xmessage: {}", e))
-                .ok();
-            e
-        })
-        .context("failed to load config")?;
+    crash::install_hook();
+    spawn::init();
+
+    // panics while starting up, e.g. in the config or while restoring the workspaces, count as
+    // crash as well so a crash loop ends up in safe mode
+    let safe_mode = crash::take_safe_mode();
+    let replace = matches.is_present("replace");
+    let instance = match panic::catch_unwind(AssertUnwindSafe(|| start(safe_mode, replace))) {
+        Ok(x) => x?,
+        Err(_) => return Err(crash::recover_startup()),
+    };
+
+    let Instance { config, ws, workspaces, session, autostart, mut ipc, watcher } = instance;
+
+    info!("entering event loop");
+    run_event_loop(config, &ws, workspaces, session, autostart, &mut ipc, watcher)
+}
+
+// everything set up by `start` for the event loop
+struct Instance {
+    config: Config,
+    ws: XlibWindowSystem,
+    workspaces: Workspaces,
+    session: Session,
+    autostart: Autostart,
+    ipc: IpcServer,
+    watcher: Option<ConfigWatcher>,
+}
+
+// loads the config, takes over the screen and manages the existing windows
+fn start(safe_mode: bool, replace: bool) -> Result<Instance, Error> {
+    let mut config = if safe_mode {
+        warn!("xr3wm kept crashing, using the default config");
+        utils::xmessage("xr3wm kept crashing and was restarted with the default config")
+            .map_err(|e| warn!("failed to run xmessage: {}", e))
+            .ok();
+        Config::default()
+    } else {
+        info!("loading config");
+        Config::load()
+            .inspect_err(|e| {
+                let error = utils::concat_error_chain(e);
+                utils::xmessage(&format!("failed to load config:\n{}", error))
+                    .map_err(|e| warn!("failed to run xmessage: {}", e))
+                    .ok();
+            })
+            .context("failed to load config")?
+    };
+
+    // a restart in place picks up the saved workspace state, a state file left behind otherwise
+    // belongs to a previous X session
+    let restarted = ::std::env::var_os(commands::RESTART_ENV).is_some();
+    ::std::env::remove_var(commands::RESTART_ENV);
+    if !restarted && paths::workspace_state().exists() {
+        warn!("removing stale workspace state");
+        ::std::fs::remove_file(paths::workspace_state()).ok();
+    }
+
+    // the connection of the instance that restarted might not be closed by the X server yet
+    let xws = XlibWindowSystem::new();
+    let ws = &xws;
+    ws.manage_screen(replace || restarted)?;
+
+    let issues = config.validate(Some(ws.get_screen_infos().len()));
+    for issue in issues.iter() {
+        match issue.severity {
+            Severity::Warning => warn!("config: {}", issue.message),
+            Severity::Error => error!("config: {}", issue.message),
+        }
+    }
+
+    if issues.iter().any(|x| x.severity == Severity::Error) {
+        let msg = issues.iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+        utils::xmessage(&format!("invalid config:\n{}", msg))
+            .map_err(|e| warn!("failed to run xmessage: {}", e))
+            .ok();
+        bail!("invalid config");
+    }
 
-    let ws = &XlibWindowSystem::new();
     ws.grab_modifier(config.mod_key);
 
-    let workspaces = Workspaces::new(&config, ws.get_screen_infos().len(), &ws.get_windows());
+    let mut autostart = Autostart::new();
+    autostart.start(&config, false);
+
+    let session = Session::load(&paths::session_file(), config.session_restore && !restarted)
+        .unwrap_or_else(|e| {
+            warn!("failed to load session: {}", utils::concat_error_chain(&e));
+            Session::new(&paths::session_file())
+        });
+
+    let mut workspaces = Workspaces::new(ws, &config);
+    adopt_windows(&config, ws, &mut workspaces);
 
     if let Some(ref mut statusbar) = config.statusbar {
         statusbar.start()
             .context("failed to start statusbar")?;
     }
 
-    info!("entering event loop");
-    run_event_loop(config, &ws, workspaces)
-}
-
-fn run_event_loop(mut config: Config, ws: &XlibWindowSystem, mut workspaces: Workspaces) -> Result<(), Error> {
-    loop {
-        match ws.get_event() {
-            XMapRequest(window) => {
-                debug!("XMapRequest: {}", window);
-                if !workspaces.contains(window) {
-                    let class = ws.get_class_name(window);
-                    let mut is_hooked = false;
-
-                    for hook in config.manage_hooks.iter() {
-                        if hook.class_name == class {
-                            is_hooked = true;
-                            hook.cmd.call(ws, &mut workspaces, &config, window);
-                        }
-                    }
+    let ipc = IpcServer::new(&ws.get_display_name())
+        .context("failed to start ipc server")?;
+    ws.set_root_property_string(SOCKET_ENV, &ipc.path().to_string_lossy());
 
-                    if !is_hooked {
-                        workspaces.add_window(None, ws, &config, window);
-                    }
-                }
-            }
-            XDestroy(window) => {
-                if workspaces.contains(window) {
-                    debug!("XDestroy: {}", window);
-                    workspaces.remove_window(ws, &config, window);
+    let socket = ipc.path().to_path_buf();
+    ws.on_connection_lost(move || {
+        Statusbar::kill_running();
+        ::std::fs::remove_file(&socket).ok();
+    });
+
+    let mut watcher = None;
+    update_watcher(&config, &mut watcher);
+
+    autostart.start(&config, true);
+
+    Ok(Instance {
+        config,
+        ws: xws,
+        workspaces,
+        session,
+        autostart,
+        ipc,
+        watcher,
+    })
+}
+
+// loads and validates the config without connecting to X, returns false if it has errors
+fn check_config() -> Result<bool, Error> {
+    let config = Config::load()
+        .context("failed to load config")?;
+
+    let issues = config.validate(None);
+    for issue in issues.iter() {
+        eprintln!("{}", issue);
+    }
+
+    let valid = issues.iter().all(|x| x.severity != Severity::Error);
+    if valid {
+        println!("config ok");
+    }
+
+    Ok(valid)
+}
+
+fn run_event_loop(mut config: Config, ws: &XlibWindowSystem, mut workspaces: Workspaces, mut session: Session, mut autostart: Autostart, ipc: &mut IpcServer, mut watcher: Option<ConfigWatcher>) -> Result<(), Error> {
+    let mut reloader = Reloader::default();
+    let mut launcher = Launcher::default();
+
+    // a panic while handling an event restarts xr3wm instead of leaving the clients without
+    // a window manager
+    let result = panic::catch_unwind(AssertUnwindSafe(|| -> Result<(), Error> {
+        loop {
+            while ws.has_pending_events() {
+                let event = ws.get_event();
+
+                if let XSelectionClear(_) = event {
+                    info!("replaced by another window manager");
+                    release_windows(ws, &workspaces);
+                    return Ok(());
                 }
+
+                let (mut events, state) = if ipc.has_subscribers() {
+                    let state = if events::changes_state(&event, ws) { Some(State::new(&workspaces)) } else { None };
+                    (Event::from_xevent(&event, ws, &workspaces), state)
+                } else {
+                    (Vec::new(), None)
+                };
+
+                handle_event(event, &config, ws, &mut workspaces, &mut session, &mut launcher, &mut reloader);
+                handle_errors(&config, ws, &mut workspaces);
+
+                events.extend(diff(state, &workspaces));
+                events.extend(reloader.take_events());
+                update_statusbar(&mut config, ws, &workspaces, &reloader);
+                update_session(&config, ws, &workspaces, &mut session);
+                ipc.broadcast(&events);
             }
-            XUnmapNotify(window, send) => {
-                if send && workspaces.contains(window) {
-                    debug!("XUnmapNotify: {}", window);
-                    workspaces.remove_window(ws, &config, window);
+
+            if reloader.take_ready() {
+                let state = snapshot(ipc, &workspaces);
+                let restart = reloader.swap(&mut config, ws, &mut workspaces);
+
+                let mut events = reloader.take_events();
+                if events.iter().any(|x| matches!(*x, Event::Reload { change: ReloadChange::Done, .. })) {
+                    update_watcher(&config, &mut watcher);
+                    autostart.start(&config, false);
+                    autostart.start(&config, true);
                 }
-            }
-            XPropertyNotify(window, atom, _) => {
-                if atom == ws.get_atom("WM_HINTS") {
-                    if let Some(workspace) = workspaces.find_window(window) {
-                        workspace.set_urgency(ws.is_urgent(window), ws, &config, window);
+
+                events.extend(diff(state, &workspaces));
+                update_statusbar(&mut config, ws, &workspaces, &reloader);
+                update_session(&config, ws, &workspaces, &mut session);
+                ipc.broadcast(&events);
+
+                // the events about the restart have been sent, subscribers see the connection close next
+                if restart {
+                    session.flush(true);
+                    if let Err(e) = commands::restart(ws, &workspaces) {
+                        reloader.fail(&e);
+                        update_statusbar(&mut config, ws, &workspaces, &reloader);
+                        ipc.broadcast(&reloader.take_events());
                     }
                 }
             }
-            XConfigurationNotify(_) => {
-                workspaces.rescreen(ws, &config);
-            }
-            XConfigurationRequest(window, changes, mask) => {
-                let unmanaged = workspaces.is_unmanaged(window) || !workspaces.contains(window);
-                ws.configure_window(window, changes, mask, unmanaged);
-            }
-            XEnterNotify(window) => {
-                trace!("XEnterNotify: {}", window);
-                workspaces.focus_window(ws, &config, window);
-            }
-            XFocusOut(_) => {
-                trace!("XFocusOut");
-                workspaces.current_mut().unfocus_window(ws, &config);
+
+            let fds: Vec<_> = Some(ws.get_connection_fd()).into_iter()
+                .chain(reloader.fd())
+                .chain(watcher.as_ref().map(|x| x.fd()))
+                .collect();
+            // the swap of a reload talks to the X server, events Xlib read meanwhile are queued
+            // without waking up the poll
+            let timeout = if ws.has_pending_events() {
+                Some(Duration::from_millis(0))
+            } else {
+                // a change during a build waits for the build, it must not wake up the poll meanwhile
+                watcher.as_ref().filter(|_| reloader.fd().is_none()).and_then(|x| x.timeout()).into_iter()
+                    .chain(reloader.timeout())
+                    .chain(session.timeout())
+                    .min()
+            };
+            ipc.wait(&fds, timeout);
+
+            let state = snapshot(ipc, &workspaces);
+            let mut handled = false;
+            ipc.process(|request| {
+                handled = true;
+                handle_ipc_request(request, &config, ws, &mut workspaces, &mut launcher, &mut reloader).to_json()
+            });
+            handle_errors(&config, ws, &mut workspaces);
+
+            reloader.poll();
+            autostart.reap(&config);
+            session.flush(false);
+
+            if let Some(ref mut watcher) = watcher {
+                watcher.read_events();
+
+                // a change during a build is picked up once it finished
+                if reloader.fd().is_none() && watcher.take_change() {
+                    info!("config changed, reloading");
+                    reloader.start(false);
+                }
             }
-            XButtonPress(window) => {
-                workspaces.focus_window(ws, &config, window);
+
+            let expired = reloader.expire();
+            let mut events = reloader.take_events();
+            if handled || expired || !events.is_empty() {
+                events.extend(diff(state, &workspaces));
+                update_statusbar(&mut config, ws, &workspaces, &reloader);
+                update_session(&config, ws, &workspaces, &mut session);
+                ipc.broadcast(&events);
             }
-            XKeyPress(_, mods, key) => {
-                trace!("XKeyPress: {}, {}", mods, key);
-                let mods = mods & !(config.mod_key | 0b10010);
-
-                for binding in config.keybindings.iter() {
-                    if binding.mods == mods && binding.key == key {
-                        binding.cmd.call(ws, &mut workspaces, &config)
-                            .map_err(|e| error!("{}", utils::concat_error_chain(&e)))
-                            .ok();
+        }
+    }));
+
+    match result {
+        Ok(x) => x,
+        Err(_) => Err(crash::recover(&config, ws, &workspaces, &mut session)),
+    }
+}
+
+// the workspace state to derive events from, only taken if there are subscribers
+fn snapshot(ipc: &IpcServer, workspaces: &Workspaces) -> Option<State> {
+    if ipc.has_subscribers() {
+        Some(State::new(workspaces))
+    } else {
+        None
+    }
+}
+
+fn diff(state: Option<State>, workspaces: &Workspaces) -> Vec<Event> {
+    state.map(|x| x.diff(&State::new(workspaces)))
+        .unwrap_or_default()
+}
+
+// reloads have to outlive the command and started programs are tracked until their window
+// shows up, both are driven by the event loop. Indices from ipc requests are checked here
+// since the workspaces assume valid ones.
+fn call_cmd(cmd: &Cmd, config: &Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces, launcher: &mut Launcher, reloader: &mut Reloader) -> Result<(), Error> {
+    match *cmd {
+        Cmd::SwitchWorkspace(index) | Cmd::MoveToWorkspace(index) | Cmd::SpawnOn(index, _)
+            if index == 0 || index > workspaces.all().len() => {
+            bail!("workspace {} does not exist", index)
+        }
+        Cmd::SwitchScreen(screen) | Cmd::MoveToScreen(screen)
+            if screen == 0 || screen > ws.get_screen_infos().len() => {
+            bail!("screen {} does not exist", screen)
+        }
+        Cmd::Reload => {
+            debug!("Cmd::Reload");
+            reloader.start(false);
+            Ok(())
+        }
+        Cmd::Restart => {
+            debug!("Cmd::Restart");
+            reloader.start(true);
+            Ok(())
+        }
+        Cmd::SpawnOn(index, ref spawn) => {
+            debug!("Cmd::SpawnOn: {}, {}", index, spawn);
+            launcher.spawn(&commands::resolve_cwd(spawn, ws, workspaces), index - 1)
+        }
+        _ => cmd.call(ws, workspaces, config),
+    }
+}
+
+fn handle_ipc_request(request: &str, config: &Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces, launcher: &mut Launcher, reloader: &mut Reloader) -> Reply {
+    debug!("IPC: {}", request);
+
+    match request.trim() {
+        "get-tree" => return Reply::data(&Tree::new(ws, workspaces)),
+        "get-workspaces" => return Reply::data(&Tree::new(ws, workspaces).workspaces),
+        "get-windows" => return Reply::data(&tree::windows(ws, workspaces)),
+        _ => {}
+    }
+
+    let result = request.parse::<Cmd>()
+        .and_then(|cmd| call_cmd(&cmd, config, ws, workspaces, launcher, reloader));
+
+    match result {
+        Ok(_) => Reply::ok(),
+        Err(e) => Reply::err(utils::concat_error_chain(&e)),
+    }
+}
+
+fn update_statusbar(config: &mut Config, ws: &XlibWindowSystem, workspaces: &Workspaces, reloader: &Reloader) {
+    if let Some(ref mut statusbar) = config.statusbar {
+        statusbar.set_message(reloader.message());
+        if let Err(e) = statusbar.update(ws, workspaces) {
+            error!("{}", e.context("failed to update statusbar"));
+        }
+    }
+}
+
+// maps the windows of hidden workspaces again so the next window manager finds them
+fn release_windows(ws: &XlibWindowSystem, workspaces: &Workspaces) {
+    for workspace in workspaces.all().iter().filter(|x| !x.is_visible()) {
+        for window in workspace.all() {
+            ws.show_window(window);
+        }
+    }
+    ws.close();
+}
+
+// removes managed windows that X errors revealed to be gone
+fn handle_errors(config: &Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces) {
+    for error in ws.take_errors() {
+        let window = error.resource;
+        if workspaces.contains(window) && (error.is_gone() || !ws.window_exists(window)) {
+            debug!("removing window {} after an X error", window);
+            workspaces.unswallow(ws, config, window);
+            workspaces.remove_window(ws, config, window);
+        }
+    }
+}
+
+// creates or drops the watcher of the config file as `watch_config` says
+fn update_watcher(config: &Config, watcher: &mut Option<ConfigWatcher>) {
+    if config.watch_config && watcher.is_none() {
+        match ConfigWatcher::new() {
+            Ok(x) => *watcher = Some(x),
+            Err(e) => error!("{}", utils::concat_error_chain(&e)),
+        }
+    } else if !config.watch_config && watcher.is_some() {
+        debug!("no longer watching the config");
+        *watcher = None;
+    }
+}
+
+fn update_session(config: &Config, ws: &XlibWindowSystem, workspaces: &Workspaces, session: &mut Session) {
+    if config.session_restore {
+        session.update(ws, workspaces);
+    }
+}
+
+fn is_hooked(window: Window, config: &Config, ws: &XlibWindowSystem) -> bool {
+    let class = ws.get_class_name(window);
+    config.manage_hooks.iter().any(|x| x.class_name == class)
+}
+
+// `index` overrides the workspace, the manage hooks still apply otherwise
+fn manage_window(window: Window, index: Option<usize>, config: &Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces) {
+    let class = ws.get_class_name(window);
+    let mut is_hooked = false;
+
+    for hook in config.manage_hooks.iter() {
+        if hook.class_name == class {
+            is_hooked = true;
+            hook.cmd.call(ws, workspaces, config, window, index);
+        }
+    }
+
+    if !is_hooked {
+        workspaces.add_window(index, ws, config, window);
+    }
+}
+
+// manages the windows mapped before xr3wm started, e.g. by .xinitrc or under a crashed window manager
+fn adopt_windows(config: &Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces) {
+    let windows: Vec<Window> = ws.get_mapped_windows()
+        .into_iter()
+        .filter(|&x| !workspaces.contains(x))
+        .collect();
+
+    for window in windows {
+        debug!("adopting window {}", window);
+        ws.setup_managed_window(window);
+
+        match ws.get_desktop(window).filter(|&x| x < workspaces.all().len()) {
+            Some(index) => workspaces.add_window(Some(index), ws, config, window),
+            None => manage_window(window, None, config, ws, workspaces),
+        }
+
+        // the window is still mapped but might have ended up on a hidden workspace
+        if workspaces.find_window(window).map(|x| !x.is_visible()).unwrap_or(false) {
+            ws.hide_window(window);
+        }
+    }
+}
+
+fn handle_event(event: XlibEvent, config: &Config, ws: &XlibWindowSystem, workspaces: &mut Workspaces, session: &mut Session, launcher: &mut Launcher, reloader: &mut Reloader) {
+    match event {
+        XMapRequest(window) => {
+            debug!("XMapRequest: {}", window);
+            if !workspaces.contains(window) {
+                if let Some(index) = launcher.place(window, ws) {
+                    manage_window(window, Some(index), config, ws, workspaces);
+                } else if session.place(window, config, ws, workspaces) {
+                    // back where it was in the saved session
+                } else if is_hooked(window, config, ws) {
+                    // the hooks decide where the window goes, it doesn't swallow its terminal
+                    manage_window(window, None, config, ws, workspaces);
+                } else {
+                    match swallow::find_terminal(window, config, ws, workspaces) {
+                        Some(terminal) => workspaces.swallow(ws, config, terminal, window),
+                        None => workspaces.add_window(None, ws, config, window),
                     }
                 }
             }
-            _ => {}
         }
+        XDestroy(window) if workspaces.contains(window) => {
+            debug!("XDestroy: {}", window);
+            workspaces.unswallow(ws, config, window);
+            workspaces.remove_window(ws, config, window);
+        }
+        XUnmapNotify(window, true) if workspaces.contains(window) => {
+            debug!("XUnmapNotify: {}", window);
+            workspaces.unswallow(ws, config, window);
+            workspaces.remove_window(ws, config, window);
+        }
+        XPropertyNotify(window, atom, _) if atom == ws.get_atom("WM_HINTS") => {
+            if let Some(workspace) = workspaces.find_window(window) {
+                workspace.set_urgency(ws.is_urgent(window), ws, config, window);
+            }
+        }
+        XConfigurationNotify(_) => {
+            workspaces.rescreen(ws, config);
+        }
+        XConfigurationRequest(window, changes, mask) => {
+            let unmanaged = workspaces.is_unmanaged(window) || !workspaces.contains(window);
+            ws.configure_window(window, changes, mask, unmanaged);
+        }
+        XEnterNotify(window) => {
+            trace!("XEnterNotify: {}", window);
+            workspaces.focus_window(ws, config, window);
+        }
+        XFocusOut(_) => {
+            trace!("XFocusOut");
+            workspaces.current_mut().unfocus_window(ws, config);
+        }
+        XButtonPress(window) => {
+            workspaces.focus_window(ws, config, window);
+        }
+        XKeyPress(_, mods, key) => {
+            trace!("XKeyPress: {}, {}", mods, key);
+            let mods = mods & !(config.mod_key | 0b10010);
 
-        if let Some(ref mut statusbar) = config.statusbar {
-            if let Err(e) = statusbar.update(ws, &workspaces) {
-                error!("{}", e.context("failed to update statusbar"));
+            for binding in config.keybindings.iter() {
+                if binding.mods == mods && binding.key == key {
+                    call_cmd(&binding.cmd, config, ws, workspaces, launcher, reloader)
+                        .map_err(|e| error!("{}", utils::concat_error_chain(&e)))
+                        .ok();
+                }
             }
         }
+        _ => {}
     }
-
 }
 
 fn main() {
@@ -217,3 +699,30 @@ fn main() {
         ::std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(args: &[&str]) -> Vec<String> {
+        let matches = cli().get_matches_from_safe(args)
+            .unwrap_or_else(|e| panic!("failed to parse {:?}: {}", args, e));
+        matches.subcommand_matches("msg")
+            .and_then(|x| x.values_of("command"))
+            .map(|x| x.map(|x| x.to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn msg_passes_options_through() {
+        assert_eq!(msg(&["xr3wm", "msg", "exec", "--workspace", "8", "slack"]),
+                   ["exec", "--workspace", "8", "slack"]);
+        assert_eq!(msg(&["xr3wm", "msg", "exec", "--cwd", "~/src", "--env", "A=b", "--focused-cwd", "urxvt"]),
+                   ["exec", "--cwd", "~/src", "--env", "A=b", "--focused-cwd", "urxvt"]);
+    }
+
+    #[test]
+    fn msg_passes_separator_through() {
+        assert_eq!(msg(&["xr3wm", "msg", "exec", "--", "ls"]), ["exec", "--", "ls"]);
+    }
+}